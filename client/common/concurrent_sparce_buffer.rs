@@ -0,0 +1,346 @@
+extern crate handle;
+use handle::HandleLayout;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const NODE_SIZE: usize = 64;
+const SHARD_COUNT: usize = 16;
+const DEFAULT_MAX_NODES: usize = 4096;
+//the directory is allocated whole up front, 2^20 pointers is 8MB for 64M slots
+const MAX_DIRECTORY_NODES: usize = 1 << 20;
+
+/*
+ * Slot state is packed into one atomic so readers can pin a slot with a single fetch_add
+ *
+ * readers: bottom 32 bits, guards currently (or speculatively) looking at the slot
+ * LIVE: the slot holds a value
+ * PENDING: the slot was freed while readers were still out, the last one drops the value
+ * gen: top 24 bits, bumped on free
+ */
+const READER_MASK: u64 = 0xFFFF_FFFF;
+const LIVE: u64 = 1 << 32;
+const PENDING: u64 = 1 << 33;
+const GEN_SHIFT: u32 = 40;
+
+struct Slot<T> {
+    state: AtomicU64,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+struct Node<T> {
+    slots: [Slot<T>; NODE_SIZE],
+}
+
+/*
+ * Sparse buffer that can be shared across threads
+ *
+ * Allocate and Free take one of SHARD_COUNT free list locks, picked per thread
+ * Get never locks or loops, it pins the slot with a reader count
+ * A value freed while pinned is dropped by whichever reader lets go of it last
+ *
+ * The node directory is sized up front, nodes themselves are allocated on demand
+ */
+pub struct ConcurrentSparceBuffer<T, L: HandleLayout = handle::Handle32> {
+    nodes: Box<[AtomicPtr<Node<T>>]>,
+    free_lists: [Mutex<Vec<usize>>; SHARD_COUNT],
+    next_unused: AtomicUsize,
+    alloc_count: AtomicUsize,
+    _layout: PhantomData<L>,
+}
+
+unsafe impl<T: Send, L: HandleLayout> Send for ConcurrentSparceBuffer<T, L> {}
+unsafe impl<T: Send + Sync, L: HandleLayout> Sync for ConcurrentSparceBuffer<T, L> {}
+
+/*
+ * Returned by TryAllocate once every slot in the directory is taken
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError {
+    pub max_nodes: usize,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "ConcurrentSparceBuffer is out of capacity ({} nodes of {} slots)",
+            self.max_nodes, NODE_SIZE
+        );
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/*
+ * Keeps the value alive until dropped, even if another thread frees the handle meanwhile
+ */
+pub struct ConcurrentRef<'a, T, L: HandleLayout = handle::Handle32> {
+    buffer: &'a ConcurrentSparceBuffer<T, L>,
+    index: usize,
+}
+
+impl<T, L: HandleLayout> ConcurrentSparceBuffer<T, L> {
+    pub fn new() -> Self {
+        return Self::WithMaxNodes(DEFAULT_MAX_NODES);
+    }
+
+    /*
+     * The directory can't be resized while other threads read it, so capacity is fixed here
+     * max_nodes is clamped to what the handle layout can address and to 2^20 nodes,
+     * so Handle64's 2^34 node range doesn't turn into a directory too large to allocate
+     */
+    pub fn WithMaxNodes(max_nodes: usize) -> Self {
+        assert!(L::GENERATION_BITS <= 64 - GEN_SHIFT);
+        assert!((1u64 << L::INSTANCE_BITS) >= NODE_SIZE as u64);
+        let layout_max = usize::try_from(L::MAX_NODES).unwrap_or(usize::MAX);
+        let max_nodes = max_nodes.min(layout_max).min(MAX_DIRECTORY_NODES);
+
+        return Self {
+            nodes: (0..max_nodes)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            free_lists: std::array::from_fn(|_| Mutex::new(Vec::new())),
+            next_unused: AtomicUsize::new(0),
+            alloc_count: AtomicUsize::new(0),
+            _layout: PhantomData,
+        };
+    }
+
+    /*
+     * Panics once the buffer is out of capacity, see TryAllocate
+     */
+    pub fn Allocate(&self, value: T) -> handle::handle_t<T, L> {
+        return self
+            .TryAllocate(value)
+            .expect("ConcurrentSparceBuffer allocation failed");
+    }
+
+    pub fn TryAllocate(&self, value: T) -> Result<handle::handle_t<T, L>, AllocError> {
+        let index = match self.PopFree() {
+            Some(index) => index,
+            None => self.TakeUnused()?,
+        };
+        let slot = self.SlotAt(index).expect("reserved slot without a node");
+
+        unsafe { (*slot.value.get()).write(value) };
+        let state = slot.state.fetch_or(LIVE, Ordering::Release);
+        self.alloc_count.fetch_add(1, Ordering::Relaxed);
+
+        return Ok(handle::handle_t::from(
+            Generation(state),
+            index / NODE_SIZE,
+            index % NODE_SIZE,
+        ));
+    }
+
+    /*
+     * Wait free, returns None for null or stale handles
+     */
+    pub fn Get(&self, h: handle::handle_t<T, L>) -> Option<ConcurrentRef<'_, T, L>> {
+        if h.IsNull() {
+            return None;
+        }
+        let index = h.Node() * NODE_SIZE + h.Instance();
+        let slot = self.SlotAt(index)?;
+
+        let state = slot.state.fetch_add(1, Ordering::Acquire);
+        if (state & LIVE) == 0 || Generation(state) != h.Generation() {
+            self.ReleaseReader(index);
+            return None;
+        }
+        return Some(ConcurrentRef {
+            buffer: self,
+            index: index,
+        });
+    }
+
+    /*
+     * Returns false for null or stale handles, or if another thread freed it first
+     * The value is dropped right away unless a ConcurrentRef still points at it
+     */
+    pub fn Free(&self, h: handle::handle_t<T, L>) -> bool {
+        if h.IsNull() {
+            return false;
+        }
+        let index = h.Node() * NODE_SIZE + h.Instance();
+        let slot = match self.SlotAt(index) {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        let mut state = slot.state.load(Ordering::Acquire);
+        loop {
+            if (state & LIVE) == 0 || Generation(state) != h.Generation() {
+                return false;
+            }
+            let readers = state & READER_MASK;
+            let mut next = ((L::NextGeneration(Generation(state)) as u64) << GEN_SHIFT) | readers;
+            if readers != 0 {
+                next |= PENDING;
+            }
+            match slot
+                .state
+                .compare_exchange_weak(state, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(current) => state = current,
+            }
+        }
+
+        self.alloc_count.fetch_sub(1, Ordering::Relaxed);
+        if (state & READER_MASK) == 0 {
+            self.Reclaim(index);
+        }
+        return true;
+    }
+
+    pub fn Size(&self) -> usize {
+        return self.alloc_count.load(Ordering::Relaxed);
+    }
+
+    pub fn MaxNodes(&self) -> usize {
+        return self.nodes.len();
+    }
+
+    fn ReleaseReader(&self, index: usize) {
+        let slot = self.SlotAt(index).expect("reader on a slot without a node");
+        let mut state = slot.state.fetch_sub(1, Ordering::AcqRel) - 1;
+
+        //whoever takes the reader count to zero on a pending slot owns the drop
+        while (state & READER_MASK) == 0 && (state & PENDING) != 0 {
+            match slot.state.compare_exchange_weak(
+                state,
+                state & !PENDING,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    self.Reclaim(index);
+                    return;
+                }
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    /*
+     * Drops the value of a dead slot and makes it available to Allocate again
+     * Only called once per free, by the thread that saw the last reader go
+     */
+    fn Reclaim(&self, index: usize) {
+        let slot = self
+            .SlotAt(index)
+            .expect("reclaiming a slot without a node");
+        unsafe { (*slot.value.get()).assume_init_drop() };
+        self.free_lists[ShardIndex()]
+            .lock()
+            .expect("free list poisoned")
+            .push(index);
+    }
+
+    /*
+     * Pops from this thread's shard first, then steals from the others
+     */
+    fn PopFree(&self) -> Option<usize> {
+        let home = ShardIndex();
+        for offset in 0..SHARD_COUNT {
+            let shard = &self.free_lists[(home + offset) % SHARD_COUNT];
+            if let Some(index) = shard.lock().expect("free list poisoned").pop() {
+                return Some(index);
+            }
+        }
+        return None;
+    }
+
+    /*
+     * Hands out a slot that has never been used, creating its node if needed
+     */
+    fn TakeUnused(&self) -> Result<usize, AllocError> {
+        let index = self.next_unused.fetch_add(1, Ordering::Relaxed);
+        let node = index / NODE_SIZE;
+        if node >= self.nodes.len() {
+            return Err(AllocError {
+                max_nodes: self.nodes.len(),
+            });
+        }
+        if self.nodes[node].load(Ordering::Acquire).is_null() {
+            let fresh = Box::into_raw(Box::new(Node::<T> {
+                slots: std::array::from_fn(|_| Slot {
+                    state: AtomicU64::new(1 << GEN_SHIFT),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                }),
+            }));
+            //another thread may have raced us to the same node, keep theirs
+            if self.nodes[node]
+                .compare_exchange(ptr::null_mut(), fresh, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                drop(unsafe { Box::from_raw(fresh) });
+            }
+        }
+        return Ok(index);
+    }
+
+    fn SlotAt(&self, index: usize) -> Option<&Slot<T>> {
+        let node = self.nodes.get(index / NODE_SIZE)?.load(Ordering::Acquire);
+        if node.is_null() {
+            return None;
+        }
+        return Some(unsafe { &(*node).slots[index % NODE_SIZE] });
+    }
+}
+
+impl<T, L: HandleLayout> Drop for ConcurrentSparceBuffer<T, L> {
+    fn drop(&mut self) {
+        for node in self.nodes.iter_mut() {
+            let node = *node.get_mut();
+            if node.is_null() {
+                continue;
+            }
+            let mut node = unsafe { Box::from_raw(node) };
+            for slot in node.slots.iter_mut() {
+                if (*slot.state.get_mut() & LIVE) != 0 {
+                    unsafe { slot.value.get_mut().assume_init_drop() };
+                }
+            }
+        }
+    }
+}
+
+impl<'a, T, L: HandleLayout> Deref for ConcurrentRef<'a, T, L> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        let slot = self
+            .buffer
+            .SlotAt(self.index)
+            .expect("pinned slot without a node");
+        return unsafe { (*slot.value.get()).assume_init_ref() };
+    }
+}
+
+impl<'a, T, L: HandleLayout> Drop for ConcurrentRef<'a, T, L> {
+    fn drop(&mut self) {
+        self.buffer.ReleaseReader(self.index);
+    }
+}
+
+fn Generation(state: u64) -> u32 {
+    return (state >> GEN_SHIFT) as u32;
+}
+
+/*
+ * Threads are spread round robin over the free list shards
+ */
+fn ShardIndex() -> usize {
+    static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARD_COUNT;
+    }
+    return SHARD.with(|shard| *shard);
+}
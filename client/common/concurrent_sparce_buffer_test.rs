@@ -0,0 +1,173 @@
+extern crate concurrent_sparce_buffer;
+extern crate handle;
+
+use concurrent_sparce_buffer::ConcurrentSparceBuffer;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+const THREAD_COUNT: usize = 8;
+const ROUNDS: usize = 2000;
+
+/*
+ * Counts live instances so the stress tests can check nothing leaks or double drops
+ */
+struct Tracked {
+    value: usize,
+    live: &'static AtomicUsize,
+}
+
+impl Tracked {
+    fn new(value: usize, live: &'static AtomicUsize) -> Self {
+        live.fetch_add(1, Ordering::SeqCst);
+        return Self { value, live };
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.live.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn allocate_get_free() {
+    let buffer = ConcurrentSparceBuffer::<String>::new();
+    let h = buffer.Allocate(String::from("lich"));
+    assert_eq!(&*buffer.Get(h).expect("live"), "lich");
+    assert!(buffer.Free(h));
+    assert!(buffer.Get(h).is_none());
+    assert!(!buffer.Free(h));
+
+    let h2 = buffer.Allocate(String::from("wraith"));
+    assert_eq!(h2.Node(), h.Node());
+    assert_eq!(h2.Instance(), h.Instance());
+    assert!(buffer.Get(h).is_none());
+    assert_eq!(&*buffer.Get(h2).expect("live"), "wraith");
+}
+
+#[test]
+fn free_while_pinned_defers_drop() {
+    static LIVE: AtomicUsize = AtomicUsize::new(0);
+    let buffer = ConcurrentSparceBuffer::<Tracked>::new();
+    let h = buffer.Allocate(Tracked::new(5, &LIVE));
+
+    let guard = buffer.Get(h).expect("live");
+    assert!(buffer.Free(h));
+    assert_eq!(LIVE.load(Ordering::SeqCst), 1);
+    assert_eq!(guard.value, 5);
+    assert!(buffer.Get(h).is_none());
+
+    drop(guard);
+    assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn out_of_capacity() {
+    let buffer = ConcurrentSparceBuffer::<u32>::WithMaxNodes(1);
+    for i in 0..64 {
+        buffer.TryAllocate(i).expect("within capacity");
+    }
+    assert!(buffer.TryAllocate(64).is_err());
+}
+
+#[test]
+fn directory_size_is_capped() {
+    //Handle64 could address 2^34 nodes, the directory stops at 2^20
+    let buffer = ConcurrentSparceBuffer::<u32, handle::Handle64>::WithMaxNodes(usize::MAX);
+    assert_eq!(buffer.MaxNodes(), 1 << 20);
+    let h = buffer.Allocate(5);
+    assert_eq!(*buffer.Get(h).unwrap(), 5);
+
+    let buffer = ConcurrentSparceBuffer::<u32>::WithMaxNodes(usize::MAX);
+    assert_eq!(buffer.MaxNodes(), 1 << 18);
+}
+
+#[test]
+fn stress_allocate_and_free() {
+    static LIVE: AtomicUsize = AtomicUsize::new(0);
+    let buffer = ConcurrentSparceBuffer::<Tracked>::new();
+
+    thread::scope(|scope| {
+        for t in 0..THREAD_COUNT {
+            let buffer = &buffer;
+            scope.spawn(move || {
+                let mut owned = Vec::new();
+                for i in 0..ROUNDS {
+                    let value = t * ROUNDS + i;
+                    owned.push((buffer.Allocate(Tracked::new(value, &LIVE)), value));
+                    //free every other allocation so slots churn between threads
+                    if i % 2 == 1 {
+                        let (h, _) = owned.swap_remove(i % owned.len());
+                        assert!(buffer.Free(h));
+                        assert!(buffer.Get(h).is_none());
+                    }
+                    for (h, expected) in owned.iter() {
+                        if i % 64 == 0 {
+                            assert_eq!(buffer.Get(*h).expect("live").value, *expected);
+                        }
+                    }
+                }
+                for (h, expected) in owned {
+                    assert_eq!(buffer.Get(h).expect("live").value, expected);
+                    assert!(buffer.Free(h));
+                }
+            });
+        }
+    });
+
+    assert_eq!(buffer.Size(), 0);
+    assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn stress_readers_race_free() {
+    static LIVE: AtomicUsize = AtomicUsize::new(0);
+    let buffer = ConcurrentSparceBuffer::<Tracked>::new();
+    let shared = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        //writers keep replacing values, readers keep reading whatever handles are published
+        for t in 0..THREAD_COUNT / 2 {
+            let buffer = &buffer;
+            let shared = &shared;
+            scope.spawn(move || {
+                for i in 0..ROUNDS {
+                    let h = buffer.Allocate(Tracked::new(t * ROUNDS + i, &LIVE));
+                    let old = {
+                        let mut handles = shared.lock().unwrap();
+                        handles.push(h);
+                        if handles.len() > 32 {
+                            Some(handles.remove(0))
+                        } else {
+                            None
+                        }
+                    };
+                    if let Some(old) = old {
+                        assert!(buffer.Free(old));
+                    }
+                }
+            });
+        }
+        for _ in 0..THREAD_COUNT / 2 {
+            let buffer = &buffer;
+            let shared = &shared;
+            scope.spawn(move || {
+                for _ in 0..ROUNDS {
+                    let handles = shared.lock().unwrap().clone();
+                    for h in handles {
+                        if let Some(guard) = buffer.Get(h) {
+                            assert!(guard.value < THREAD_COUNT * ROUNDS);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    for h in shared.into_inner().unwrap() {
+        assert!(buffer.Free(h));
+    }
+    assert_eq!(buffer.Size(), 0);
+    assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+}
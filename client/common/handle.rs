@@ -1,22 +1,113 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::hash::Hash;
 use std::marker::PhantomData;
 
+/*
+ * Integer type a handle is stored in
+ * All bit twiddling is done in u64 and narrowed back down on store
+ */
+pub trait HandleRaw: Copy + Eq + Ord + Hash + fmt::Debug + Default + 'static {
+    fn ToBits(self) -> u64;
+    fn FromBits(bits: u64) -> Self;
+}
+
+impl HandleRaw for u16 {
+    fn ToBits(self) -> u64 {
+        return self as u64;
+    }
+    fn FromBits(bits: u64) -> Self {
+        return bits as u16;
+    }
+}
+
+impl HandleRaw for u32 {
+    fn ToBits(self) -> u64 {
+        return self as u64;
+    }
+    fn FromBits(bits: u64) -> Self {
+        return bits as u32;
+    }
+}
+
+impl HandleRaw for u64 {
+    fn ToBits(self) -> u64 {
+        return self;
+    }
+    fn FromBits(bits: u64) -> Self {
+        return bits;
+    }
+}
+
+/*
+ * Describes how a handle packs its fields, from most to least significant bit:
+ *
+ * gen: generation of the slot, 0 is reserved so a live handle is never null
+ * node: index of the node in the buffer
+ * instance: index of the slot inside the node
+ *
+ * The buffers need at least 6 instance bits (SparceBufferRc nodes are 64 wide)
+ */
+pub trait HandleLayout: 'static {
+    type Raw: HandleRaw;
+    const GENERATION_BITS: u32;
+    const NODE_BITS: u32;
+    const INSTANCE_BITS: u32;
+
+    const MAX_GENERATION: u32 = ((1u64 << Self::GENERATION_BITS) - 1) as u32;
+    const MAX_NODES: u64 = 1u64 << Self::NODE_BITS;
+
+    /*
+     * Generation a slot moves to when it is freed, wraps back around to 1
+     */
+    fn NextGeneration(gen: u32) -> u32 {
+        if gen >= Self::MAX_GENERATION {
+            return 1;
+        }
+        return gen + 1;
+    }
+}
+
+/*
+ * 8 bit generation, 18 bit node, 6 bit instance
+ * ~16M slots per buffer
+ */
+pub struct Handle32;
+
+impl HandleLayout for Handle32 {
+    type Raw = u32;
+    const GENERATION_BITS: u32 = 8;
+    const NODE_BITS: u32 = 18;
+    const INSTANCE_BITS: u32 = 6;
+}
+
+/*
+ * 24 bit generation, 34 bit node, 6 bit instance
+ */
+pub struct Handle64;
+
+impl HandleLayout for Handle64 {
+    type Raw = u64;
+    const GENERATION_BITS: u32 = 24;
+    const NODE_BITS: u32 = 34;
+    const INSTANCE_BITS: u32 = 6;
+}
+
 #[repr(transparent)]
-#[derive(Debug)]
-pub struct handle_t<T> {
-    value: i16,
-    _marker: PhantomData<T>,
+pub struct handle_t<T, L: HandleLayout = Handle32> {
+    value: L::Raw,
+    _marker: PhantomData<(T, L)>,
 }
 
-impl<T> Copy for handle_t<T> {}
-impl<T> Clone for handle_t<T> {
-    fn clone(&self) -> handle_t<T> {
+impl<T, L: HandleLayout> Copy for handle_t<T, L> {}
+impl<T, L: HandleLayout> Clone for handle_t<T, L> {
+    fn clone(&self) -> handle_t<T, L> {
         return *self;
     }
 }
 
-impl<T> handle_t<T> {
-    pub fn new(value: i16) -> Self {
+impl<T, L: HandleLayout> handle_t<T, L> {
+    pub fn new(value: L::Raw) -> Self {
         return Self {
             value: value,
             _marker: PhantomData,
@@ -24,56 +115,79 @@ impl<T> handle_t<T> {
     }
 
     /*
-     * gen: top 3 bits are the genrationn index
-     * g_index: next 8 bits is the node index
-     * i_index: last 5 bits is the instance indexx
+     * Packs the fields according to the layout L, see HandleLayout
      */
-    pub fn from(gen: u8, g_index: u8, i_index: u8) -> Self {
-        let mut value = 0;
-        value = (gen as i16) << 13;
-        value |= (g_index as i16) << 5;
-        value |= i_index as i16;
-        return Self::new(value);
+    pub fn from(gen: u32, g_index: usize, i_index: usize) -> Self {
+        debug_assert!(gen <= L::MAX_GENERATION);
+        debug_assert!((g_index as u64) < L::MAX_NODES);
+        debug_assert!((i_index as u64) < (1u64 << L::INSTANCE_BITS));
+
+        let mut value = (gen as u64) << (L::NODE_BITS + L::INSTANCE_BITS);
+        value |= (g_index as u64) << L::INSTANCE_BITS;
+        value |= i_index as u64;
+        return Self::new(L::Raw::FromBits(value));
     }
 
     pub fn null() -> Self {
-        return Self::new(0);
+        return Self::new(L::Raw::default());
     }
 
     pub fn IsNull(&self) -> bool {
-        return self.value == 0;
+        return self.Bits() == 0;
     }
 
     /*
      * Retuns the generation of handle
      */
-    pub fn Generation(&self) -> u8 {
-        return (self.value >> 13) as u8;
+    pub fn Generation(&self) -> u32 {
+        let mask = (1u64 << L::GENERATION_BITS) - 1;
+        return ((self.Bits() >> (L::NODE_BITS + L::INSTANCE_BITS)) & mask) as u32;
     }
 
     /*
      * Returns the index of the node
      */
-    pub fn Node(&self) -> u8 {
-        return ((self.value >> 5) & 0xff) as u8;
+    pub fn Node(&self) -> usize {
+        let mask = (1u64 << L::NODE_BITS) - 1;
+        return ((self.Bits() >> L::INSTANCE_BITS) & mask) as usize;
     }
 
-    pub fn Instance(&self) -> u8 {
-        return (self.value & 0x7F) as u8;
+    pub fn Instance(&self) -> usize {
+        let mask = (1u64 << L::INSTANCE_BITS) - 1;
+        return (self.Bits() & mask) as usize;
     }
 
-    pub fn Value(&self) -> i16 {
+    pub fn Value(&self) -> L::Raw {
         return self.value;
     }
+
+    /*
+     * The raw value widened to u64, for code that is generic over the layout
+     */
+    pub fn Bits(&self) -> u64 {
+        return self.value.ToBits();
+    }
 }
 
-impl<T> fmt::Display for handle_t<T> {
+impl<T, L: HandleLayout> fmt::Display for handle_t<T, L> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         return write!(f, "Node: {} Index: {}", self.Node(), self.Instance());
     }
 }
 
-impl<T> Default for handle_t<T> {
+impl<T, L: HandleLayout> fmt::Debug for handle_t<T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "handle_t {{ gen: {}, node: {}, instance: {} }}",
+            self.Generation(),
+            self.Node(),
+            self.Instance()
+        );
+    }
+}
+
+impl<T, L: HandleLayout> Default for handle_t<T, L> {
     fn default() -> Self {
         Self::null()
     }
@@ -81,28 +195,161 @@ impl<T> Default for handle_t<T> {
 
 // Implement equality/ordering/hash manually so they are based only on the
 // internal `value` and do not impose trait bounds on `T`.
-impl<T> PartialEq for handle_t<T> {
+impl<T, L: HandleLayout> PartialEq for handle_t<T, L> {
     fn eq(&self, other: &Self) -> bool {
         self.value == other.value
     }
 }
 
-impl<T> Eq for handle_t<T> {}
+impl<T, L: HandleLayout> Eq for handle_t<T, L> {}
 
-impl<T> PartialOrd for handle_t<T> {
+impl<T, L: HandleLayout> PartialOrd for handle_t<T, L> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<T> Ord for handle_t<T> {
+impl<T, L: HandleLayout> Ord for handle_t<T, L> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.value.cmp(&other.value)
     }
 }
 
-impl<T> std::hash::Hash for handle_t<T> {
+impl<T, L: HandleLayout> std::hash::Hash for handle_t<T, L> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.value.hash(state);
     }
 }
+
+/*
+ * Old to new handle mapping produced when a buffer compacts
+ * Anything holding handles into that buffer should run them through it
+ */
+pub type HandleRemap<T, L = Handle32> = HashMap<handle_t<T, L>, handle_t<T, L>>;
+
+/*
+ * Occupancy report from the node based buffers
+ * occupancy holds the number of taken slots per node, in node order
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferStats {
+    pub node_size: usize,
+    pub live: usize,
+    pub occupancy: Vec<usize>,
+}
+
+impl BufferStats {
+    /*
+     * Builds the report from the taken slot count of each node, in node order
+     */
+    pub fn FromOccupancy(node_size: usize, occupancy: Vec<usize>) -> Self {
+        return Self {
+            node_size: node_size,
+            live: occupancy.iter().sum(),
+            occupancy: occupancy,
+        };
+    }
+
+    pub fn NodeCount(&self) -> usize {
+        return self.occupancy.len();
+    }
+
+    pub fn Capacity(&self) -> usize {
+        return self.occupancy.len() * self.node_size;
+    }
+
+    /*
+     * Fraction of the allocated slots that hold a value, 1.0 for an empty buffer
+     */
+    pub fn Utilization(&self) -> f32 {
+        if self.Capacity() == 0 {
+            return 1.0;
+        }
+        return self.live as f32 / self.Capacity() as f32;
+    }
+
+    /*
+     * Fraction of nodes that a compaction would release
+     * 0.0 means the live values already sit in as few nodes as possible
+     */
+    pub fn Fragmentation(&self) -> f32 {
+        if self.NodeCount() == 0 {
+            return 0.0;
+        }
+        let needed = (self.live + self.node_size - 1) / self.node_size;
+        return (self.NodeCount() - needed) as f32 / self.NodeCount() as f32;
+    }
+}
+
+/*
+ * Slot bookkeeping CompactSlots needs from a node based buffer
+ * Slots are numbered node * NODE_SIZE + instance
+ *
+ * The buffers implement it on a private view of themselves, so none of this
+ * is reachable on a buffer that callers still hold references into
+ */
+pub trait NodeSlots<T, L: HandleLayout> {
+    const NODE_SIZE: usize;
+
+    fn NodeCount(&self) -> usize;
+    fn IsTaken(&self, slot: usize) -> bool;
+    fn HandleAt(&self, slot: usize) -> handle_t<T, L>;
+    //moves the value in the taken slot from into the free slot to, handles to from go stale
+    fn MoveSlot(&mut self, from: usize, to: usize);
+    //releases the last node, which holds no values, and returns its highest slot generation
+    fn PopNode(&mut self) -> u32;
+    fn RaiseFreshGeneration(&mut self, generation: u32);
+    fn HasRoom(&self, node: usize) -> bool;
+    fn SetNextFree(&mut self, node: usize, next: Option<usize>);
+    fn SetFreeHead(&mut self, head: Option<usize>);
+}
+
+/*
+ * Moves the values at the back of a buffer into the holes at the front
+ * and releases the nodes left empty
+ *
+ * Moved values get a new handle, the returned table maps old to new
+ * Handles that are not in the table did not move
+ * Nodes created later start past the generations of the released ones,
+ * so old handles into them never come back to life
+ */
+pub fn CompactSlots<T, L: HandleLayout, B: NodeSlots<T, L>>(
+    buffer: &mut B,
+    live: usize,
+) -> HandleRemap<T, L> {
+    let mut remap = HandleRemap::new();
+    let mut tail = buffer.NodeCount() * B::NODE_SIZE;
+
+    for hole in 0..live {
+        if buffer.IsTaken(hole) {
+            continue;
+        }
+        //there are exactly live taken slots, so one must sit past the hole
+        tail = tail - 1;
+        while !buffer.IsTaken(tail) {
+            tail = tail - 1;
+        }
+        let old = buffer.HandleAt(tail);
+        buffer.MoveSlot(tail, hole);
+        remap.insert(old, buffer.HandleAt(hole));
+    }
+
+    let needed = (live + B::NODE_SIZE - 1) / B::NODE_SIZE;
+    while buffer.NodeCount() > needed {
+        let generation = buffer.PopNode();
+        buffer.RaiseFreshGeneration(generation);
+    }
+
+    //rebuild the free list in node order, only the last node can have room left
+    let mut head = None;
+    for node in (0..buffer.NodeCount()).rev() {
+        if buffer.HasRoom(node) {
+            buffer.SetNextFree(node, head);
+            head = Some(node);
+        } else {
+            buffer.SetNextFree(node, None);
+        }
+    }
+    buffer.SetFreeHead(head);
+    return remap;
+}
@@ -1,6 +1,15 @@
+extern crate bincode;
 extern crate handle;
+extern crate serde;
+extern crate sparce_buffer;
+use bincode::Options;
+use handle::{HandleLayout, HandleRaw};
+use serde::{Deserialize, Serialize};
+use sparce_buffer::{SparceBuffer, SparceBufferHandleIter};
 use std::any::TypeId;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::marker::PhantomData;
 
 /*
  * handles are scoped within their type
@@ -10,18 +19,149 @@ use std::collections::HashMap;
  *
  */
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
-struct handle_uuid {
-    handle_value: i16,
+pub struct handle_uuid {
+    handle_value: u64,
     type_id: TypeId,
 }
 
 impl handle_uuid {
-    fn From<T: 'static>(handle: handle::handle_t<T>) -> Self {
+    pub fn From<T: 'static, L: HandleLayout>(handle: handle::handle_t<T, L>) -> Self {
         return Self {
-            handle_value: handle.Value(),
+            handle_value: handle.Bits(),
             type_id: TypeId::of::<T>(),
         };
     }
+
+    pub fn TypeId(&self) -> TypeId {
+        return self.type_id;
+    }
+
+    /*
+     * Gets the typed handle back, None if the uuid is for a different type
+     */
+    pub fn Downcast<T: 'static, L: HandleLayout>(&self) -> Option<handle::handle_t<T, L>> {
+        if self.type_id != TypeId::of::<T>() {
+            return None;
+        }
+        return Some(handle::handle_t::new(L::Raw::FromBits(self.handle_value)));
+    }
+}
+
+type LinkMap = HashMap<handle_uuid, HashMap<TypeId, Vec<handle_uuid>>>;
+
+/*
+ * How many links a type pair allows, read as A to B for links from an A to a B
+ *
+ * OneToOne: an A links to one B and a B is linked from one A, a drawable and its transform
+ * OneToMany: an A links to any number of Bs, each B is linked from one A
+ * ManyToOne: any number of As link to the same B, each A links to one B
+ * ManyToMany: no limit either way
+ *
+ * The same link twice is rejected whatever the cardinality
+ */
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Cardinality {
+    OneToOne,
+    OneToMany,
+    ManyToOne,
+    ManyToMany,
+}
+
+impl Cardinality {
+    //an A links to at most one B
+    fn SingleTarget(self) -> bool {
+        return self == Cardinality::OneToOne || self == Cardinality::ManyToOne;
+    }
+
+    //a B is linked from at most one A
+    fn SingleSource(self) -> bool {
+        return self == Cardinality::OneToOne || self == Cardinality::OneToMany;
+    }
+}
+
+/*
+ * Why a link was rejected, the handles are the links already in the way
+ */
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LinkError {
+    AlreadyLinked,
+    //h1 already links to this B and the pair allows only one
+    TargetTaken(handle_uuid),
+    //h2 is already linked from this A and the pair allows only one
+    SourceTaken(handle_uuid),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            LinkError::AlreadyLinked => write!(f, "LinkTable: handles are already linked"),
+            LinkError::TargetTaken(existing) => write!(
+                f,
+                "LinkTable: source already links to {}",
+                existing.handle_value
+            ),
+            LinkError::SourceTaken(existing) => write!(
+                f,
+                "LinkTable: target already linked from {}",
+                existing.handle_value
+            ),
+        };
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/*
+ * Serialized form of a LinkTable, see Snapshot
+ *
+ * types holds the stable name of every type that appears, pairs refer to them by index
+ * Links are grouped by type pair and sorted, so the same table always encodes to the same bytes
+ */
+#[derive(Serialize, Deserialize)]
+struct LinkImage {
+    layout: (u32, u32, u32),
+    types: Vec<String>,
+    pairs: Vec<PairImage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PairImage {
+    from_type: u32,
+    to_type: u32,
+    links: Vec<(u64, u64)>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Encoding(bincode::Error),
+    //a linked type was never given a name with RegisterType
+    UnnamedType(TypeId),
+    //the bytes decoded but can't be loaded into this table
+    Corrupt(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            SnapshotError::Encoding(err) => write!(f, "LinkTable snapshot encoding: {}", err),
+            SnapshotError::UnnamedType(type_id) => {
+                write!(
+                    f,
+                    "LinkTable snapshot: {:?} has no registered name",
+                    type_id
+                )
+            }
+            SnapshotError::Corrupt(reason) => write!(f, "LinkTable snapshot corrupt: {}", reason),
+        };
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(err: bincode::Error) -> Self {
+        return SnapshotError::Encoding(err);
+    }
 }
 
 /*
@@ -30,52 +170,550 @@ impl handle_uuid {
  * IE if we have a Drawable, it should have a pose, but those are seperate
  * allocations. So we keep a link map that associates them that we can do
  * queries against
+ *
+ * Links point one way, from h1 to h2. reverse_map holds every link again
+ * keyed by h2 so we can answer who links to a handle without a scan
+ *
+ * Type pairs that were never declared are many to many
+ *
+ * TypeId changes from build to build, so to save or send a table every linked type
+ * is registered under a stable name first
  */
-pub struct LinkTable {
-    link_map: HashMap<handle_uuid, HashMap<TypeId, Vec<handle_uuid>>>,
+pub struct LinkTable<L: HandleLayout = handle::Handle32> {
+    link_map: LinkMap,
+    reverse_map: LinkMap,
+    cardinality: HashMap<(TypeId, TypeId), Cardinality>,
+    type_names: HashMap<TypeId, &'static str>,
+    name_types: HashMap<&'static str, TypeId>,
+    _layout: PhantomData<L>,
 }
 
-impl LinkTable {
+impl<L: HandleLayout> LinkTable<L> {
     pub fn new() -> Self {
         return Self {
             link_map: HashMap::new(),
+            reverse_map: HashMap::new(),
+            cardinality: HashMap::new(),
+            type_names: HashMap::new(),
+            name_types: HashMap::new(),
+            _layout: PhantomData,
         };
     }
 
-    /**
-     * There is hard assumption that there is only one link per type
-     * IE we can't link two drawables to the same transform
+    /*
+     * Gives T the name its links are saved under
+     * A type keeps its first name and a name its first type, anything else is a bug and panics
+     */
+    pub fn RegisterType<T: 'static>(&mut self, name: &'static str) {
+        let type_id = TypeId::of::<T>();
+        let registered = *self.name_types.entry(name).or_insert(type_id);
+        assert!(
+            registered == type_id,
+            "LinkTable: link type name {} is already taken",
+            name
+        );
+        let registered = *self.type_names.entry(type_id).or_insert(name);
+        assert!(
+            registered == name,
+            "LinkTable: {} is already registered as {}",
+            std::any::type_name::<T>(),
+            registered
+        );
+    }
+
+    /*
+     * Sets how many links from A to B are allowed
+     * Fails, leaving the old cardinality, if links already in the table would break the new one
+     */
+    pub fn Declare<A: 'static, B: 'static>(
+        &mut self,
+        cardinality: Cardinality,
+    ) -> Result<(), LinkError> {
+        let type_a = TypeId::of::<A>();
+        let type_b = TypeId::of::<B>();
+        if cardinality.SingleTarget() {
+            if let Some(links) = FindCrowded(&self.link_map, type_a, type_b) {
+                return Err(LinkError::TargetTaken(links[0]));
+            }
+        }
+        if cardinality.SingleSource() {
+            if let Some(links) = FindCrowded(&self.reverse_map, type_b, type_a) {
+                return Err(LinkError::SourceTaken(links[0]));
+            }
+        }
+
+        self.cardinality.insert((type_a, type_b), cardinality);
+        return Ok(());
+    }
+
+    pub fn CardinalityOf<A: 'static, B: 'static>(&self) -> Cardinality {
+        return self.PairCardinality(TypeId::of::<A>(), TypeId::of::<B>());
+    }
+
+    /*
+     * Links h1 to h2 if the cardinality declared for A and B allows it
+     * IE with Drawable to Transform declared one to one we can't link two drawables to the same transform
      */
     pub fn Link<A: 'static, B: 'static>(
         &mut self,
-        h1: handle::handle_t<A>,
-        h2: handle::handle_t<B>,
-    ) {
+        h1: handle::handle_t<A, L>,
+        h2: handle::handle_t<B, L>,
+    ) -> Result<(), LinkError> {
+        return self.LinkUuid(handle_uuid::From(h1), handle_uuid::From(h2));
+    }
+
+    /*
+     * Removes the link from h1 to h2, returns false if there was none
+     */
+    pub fn Unlink<A: 'static, B: 'static>(
+        &mut self,
+        h1: handle::handle_t<A, L>,
+        h2: handle::handle_t<B, L>,
+    ) -> bool {
         let h1_uuid = handle_uuid::From(h1);
         let h2_uuid = handle_uuid::From(h2);
 
-        self.link_map
-            .entry(h1_uuid)
-            .or_insert_with(|| HashMap::new())
-            .entry(TypeId::of::<B>())
-            .or_insert_with(|| Vec::new())
-            .push(h2_uuid);
+        if !RemoveLink(&mut self.link_map, h1_uuid, h2_uuid) {
+            return false;
+        }
+        RemoveLink(&mut self.reverse_map, h2_uuid, h1_uuid);
+        return true;
+    }
+
+    /*
+     * Removes every link from and to h
+     *
+     * With cascade the handles h links to are removed the same way, and what they link to after
+     * that, so despawning a creature also takes its drawable and the drawable's transform
+     * A handle something else still links to is shared and is left alone, only its link from
+     * the removed handle goes
+     * Returns the handles removed by the cascade, not h itself, so they can be freed from their buffers
+     */
+    pub fn RemoveAll<T: 'static>(
+        &mut self,
+        h: handle::handle_t<T, L>,
+        cascade: bool,
+    ) -> Vec<handle_uuid> {
+        let root = handle_uuid::From(h);
+        let mut removed = Vec::new();
+        let mut visited = HashSet::new();
+        visited.insert(root);
+
+        let mut pending = vec![root];
+        while let Some(uuid) = pending.pop() {
+            let linked = self.RemoveUuid(uuid);
+            if !cascade {
+                continue;
+            }
+            for target in linked {
+                //a target linked from several removed handles is checked again as each goes
+                if self.reverse_map.contains_key(&target) {
+                    continue;
+                }
+                if visited.insert(target) {
+                    removed.push(target);
+                    pending.push(target);
+                }
+            }
+        }
+        return removed;
     }
 
     pub fn GetLinkedHandles<A: 'static, B: 'static>(
         &self,
-        h1: handle::handle_t<A>,
-    ) -> Vec<handle::handle_t<B>> {
-        let h1_uuid = handle_uuid::From(h1);
-        let type_id_b = std::any::TypeId::of::<B>();
-        if let Some(type_map) = self.link_map.get(&h1_uuid) {
-            if let Some(vec_uuids) = type_map.get(&type_id_b) {
-                return vec_uuids
-                    .iter()
-                    .map(|uuid| handle::handle_t::<B>::new(uuid.handle_value))
-                    .collect();
+        h1: handle::handle_t<A, L>,
+    ) -> Vec<handle::handle_t<B, L>> {
+        return LinksOf::<B, L>(&self.link_map, handle_uuid::From(h1));
+    }
+
+    /*
+     * The B that h1 links to, meant for pairs where an A has at most one
+     * None when there is no link, including for pairs that were never declared
+     * If h1 does link to several the earliest is returned, GetLinkedHandles has them all
+     */
+    pub fn GetLinked<A: 'static, B: 'static>(
+        &self,
+        h1: handle::handle_t<A, L>,
+    ) -> Option<handle::handle_t<B, L>> {
+        let links = Links(&self.link_map, handle_uuid::From(h1), TypeId::of::<B>());
+        return links.first().and_then(|uuid| uuid.Downcast());
+    }
+
+    /*
+     * The A that links to h2, meant for pairs where a B has at most one
+     * None and earliest link work as in GetLinked
+     */
+    pub fn GetLinking<A: 'static, B: 'static>(
+        &self,
+        h2: handle::handle_t<B, L>,
+    ) -> Option<handle::handle_t<A, L>> {
+        let links = Links(&self.reverse_map, handle_uuid::From(h2), TypeId::of::<A>());
+        return links.first().and_then(|uuid| uuid.Downcast());
+    }
+
+    /*
+     * The handles of type A that link to h2, the reverse of GetLinkedHandles
+     */
+    pub fn GetLinkingHandles<A: 'static, B: 'static>(
+        &self,
+        h2: handle::handle_t<B, L>,
+    ) -> Vec<handle::handle_t<A, L>> {
+        return LinksOf::<A, L>(&self.reverse_map, handle_uuid::From(h2));
+    }
+
+    /*
+     * Every link in the table as (h1, h2), in no particular order
+     */
+    pub fn Iter(&self) -> impl Iterator<Item = (handle_uuid, handle_uuid)> + '_ {
+        return self
+            .link_map
+            .iter()
+            .flat_map(|(from, type_map)| type_map.values().flatten().map(move |to| (*from, *to)));
+    }
+
+    /*
+     * Every live A in roots that links to a B, together with that B
+     *
+     * Walks the root buffer once and resolves the links as it goes, nothing is allocated,
+     * so it is cheap enough to run every frame. Meant for pairs with one link per A,
+     * when there are more only the first is used. Links to freed slots are skipped
+     */
+    pub fn Join<'a, A: 'static, B: 'static>(
+        &'a self,
+        roots: &'a SparceBuffer<A, L>,
+        b: &'a SparceBuffer<B, L>,
+    ) -> LinkJoin<'a, A, B, L> {
+        return LinkJoin {
+            links: self,
+            roots: roots.IterWithHandles(),
+            b: b,
+        };
+    }
+
+    /*
+     * Same as Join for roots that link to both a B and a C
+     */
+    pub fn Join3<'a, A: 'static, B: 'static, C: 'static>(
+        &'a self,
+        roots: &'a SparceBuffer<A, L>,
+        b: &'a SparceBuffer<B, L>,
+        c: &'a SparceBuffer<C, L>,
+    ) -> LinkJoin3<'a, A, B, C, L> {
+        return LinkJoin3 {
+            links: self,
+            roots: roots.IterWithHandles(),
+            b: b,
+            c: c,
+        };
+    }
+
+    /*
+     * Rewrites every link to or from a handle of type T after its buffer compacted
+     */
+    pub fn Remap<T: 'static>(&mut self, remap: &handle::HandleRemap<T, L>) {
+        let uuid_remap: HashMap<handle_uuid, handle_uuid> = remap
+            .iter()
+            .map(|(old, new)| (handle_uuid::From(*old), handle_uuid::From(*new)))
+            .collect();
+
+        RemapLinks(&mut self.link_map, &uuid_remap, TypeId::of::<T>());
+        RemapLinks(&mut self.reverse_map, &uuid_remap, TypeId::of::<T>());
+    }
+
+    /*
+     * Encodes every link, with handle types written as their registered names
+     * Fails if a linked type has no name
+     */
+    pub fn Snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+        let mut pairs: HashMap<(TypeId, TypeId), Vec<(u64, u64)>> = HashMap::new();
+        for (from, to) in self.Iter() {
+            pairs
+                .entry((from.type_id, to.type_id))
+                .or_insert_with(|| Vec::new())
+                .push((from.handle_value, to.handle_value));
+        }
+
+        let mut names = Vec::new();
+        for (from_type, to_type) in pairs.keys() {
+            for type_id in [from_type, to_type] {
+                let name = self
+                    .type_names
+                    .get(type_id)
+                    .ok_or(SnapshotError::UnnamedType(*type_id))?;
+                names.push(*name);
+            }
+        }
+        names.sort();
+        names.dedup();
+        let index_of = |type_id: TypeId| {
+            return names.binary_search(&self.type_names[&type_id]).expect("") as u32;
+        };
+
+        let mut pair_images: Vec<PairImage> = pairs
+            .into_iter()
+            .map(|((from_type, to_type), mut links)| {
+                links.sort();
+                return PairImage {
+                    from_type: index_of(from_type),
+                    to_type: index_of(to_type),
+                    links: links,
+                };
+            })
+            .collect();
+        pair_images.sort_by_key(|pair| (pair.from_type, pair.to_type));
+
+        let image = LinkImage {
+            layout: (L::GENERATION_BITS, L::NODE_BITS, L::INSTANCE_BITS),
+            types: names.iter().map(|name| name.to_string()).collect(),
+            pairs: pair_images,
+        };
+        return Ok(SnapshotOptions().serialize(&image)?);
+    }
+
+    /*
+     * Replaces the links with the ones in Snapshot bytes
+     * Every type named in them has to be registered here, the declared cardinalities are
+     * checked as the links go in. On any error the table is left as it was
+     */
+    pub fn Restore(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let image: LinkImage = SnapshotOptions().deserialize(bytes)?;
+        let layout = (L::GENERATION_BITS, L::NODE_BITS, L::INSTANCE_BITS);
+        if image.layout != layout {
+            return Err(SnapshotError::Corrupt(format!(
+                "snapshot was taken with handle layout {:?}, expected {:?}",
+                image.layout, layout
+            )));
+        }
+        let type_ids =
+            image
+                .types
+                .iter()
+                .map(|name| {
+                    return self.name_types.get(name.as_str()).cloned().ok_or_else(|| {
+                        SnapshotError::Corrupt(format!("unknown link type {}", name))
+                    });
+                })
+                .collect::<Result<Vec<TypeId>, SnapshotError>>()?;
+        let type_at = |index: u32| {
+            return type_ids.get(index as usize).cloned().ok_or_else(|| {
+                SnapshotError::Corrupt(format!("type index {} out of {}", index, type_ids.len()))
+            });
+        };
+
+        let mut restored = LinkTable::<L>::new();
+        restored.cardinality = self.cardinality.clone();
+        for pair in image.pairs.iter() {
+            let from_type = type_at(pair.from_type)?;
+            let to_type = type_at(pair.to_type)?;
+            for (from, to) in pair.links.iter() {
+                let from = handle_uuid {
+                    handle_value: *from,
+                    type_id: from_type,
+                };
+                let to = handle_uuid {
+                    handle_value: *to,
+                    type_id: to_type,
+                };
+                restored
+                    .LinkUuid(from, to)
+                    .map_err(|err| SnapshotError::Corrupt(err.to_string()))?;
+            }
+        }
+
+        self.link_map = restored.link_map;
+        self.reverse_map = restored.reverse_map;
+        return Ok(());
+    }
+
+    fn PairCardinality(&self, from_type: TypeId, to_type: TypeId) -> Cardinality {
+        return self
+            .cardinality
+            .get(&(from_type, to_type))
+            .cloned()
+            .unwrap_or(Cardinality::ManyToMany);
+    }
+
+    fn LinkUuid(&mut self, h1_uuid: handle_uuid, h2_uuid: handle_uuid) -> Result<(), LinkError> {
+        let cardinality = self.PairCardinality(h1_uuid.type_id, h2_uuid.type_id);
+
+        let targets = Links(&self.link_map, h1_uuid, h2_uuid.type_id);
+        if targets.contains(&h2_uuid) {
+            return Err(LinkError::AlreadyLinked);
+        }
+        if cardinality.SingleTarget() && !targets.is_empty() {
+            return Err(LinkError::TargetTaken(targets[0]));
+        }
+        let sources = Links(&self.reverse_map, h2_uuid, h1_uuid.type_id);
+        if cardinality.SingleSource() && !sources.is_empty() {
+            return Err(LinkError::SourceTaken(sources[0]));
+        }
+
+        InsertLink(&mut self.link_map, h1_uuid, h2_uuid);
+        InsertLink(&mut self.reverse_map, h2_uuid, h1_uuid);
+        return Ok(());
+    }
+
+    //looks up h's first link to a T and reads it out of buffer
+    fn Resolve<'b, A: 'static, T: 'static>(
+        &self,
+        h: handle::handle_t<A, L>,
+        buffer: &'b SparceBuffer<T, L>,
+    ) -> Option<&'b T> {
+        let links = Links(&self.link_map, handle_uuid::From(h), TypeId::of::<T>());
+        return buffer.TryGet(links.first()?.Downcast()?);
+    }
+
+    //drops uuid's links in both directions and returns the handles it linked to
+    fn RemoveUuid(&mut self, uuid: handle_uuid) -> Vec<handle_uuid> {
+        let mut linked = Vec::new();
+        if let Some(type_map) = self.link_map.remove(&uuid) {
+            for target in type_map.into_values().flatten() {
+                RemoveLink(&mut self.reverse_map, target, uuid);
+                linked.push(target);
+            }
+        }
+        if let Some(type_map) = self.reverse_map.remove(&uuid) {
+            for source in type_map.into_values().flatten() {
+                RemoveLink(&mut self.link_map, source, uuid);
+            }
+        }
+        return linked;
+    }
+}
+
+pub struct LinkJoin<'a, A, B, L: HandleLayout = handle::Handle32> {
+    links: &'a LinkTable<L>,
+    roots: SparceBufferHandleIter<'a, A, L>,
+    b: &'a SparceBuffer<B, L>,
+}
+
+pub struct LinkJoin3<'a, A, B, C, L: HandleLayout = handle::Handle32> {
+    links: &'a LinkTable<L>,
+    roots: SparceBufferHandleIter<'a, A, L>,
+    b: &'a SparceBuffer<B, L>,
+    c: &'a SparceBuffer<C, L>,
+}
+
+impl<'a, A: 'static, B: 'static, L: HandleLayout> Iterator for LinkJoin<'a, A, B, L> {
+    type Item = (handle::handle_t<A, L>, &'a A, &'a B);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (h, a) in self.roots.by_ref() {
+            if let Some(b) = self.links.Resolve(h, self.b) {
+                return Some((h, a, b));
+            }
+        }
+        return None;
+    }
+}
+
+impl<'a, A: 'static, B: 'static, C: 'static, L: HandleLayout> Iterator
+    for LinkJoin3<'a, A, B, C, L>
+{
+    type Item = (handle::handle_t<A, L>, &'a A, &'a B, &'a C);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (h, a) in self.roots.by_ref() {
+            let b = match self.links.Resolve(h, self.b) {
+                Some(b) => b,
+                None => continue,
+            };
+            if let Some(c) = self.links.Resolve(h, self.c) {
+                return Some((h, a, b, c));
+            }
+        }
+        return None;
+    }
+}
+
+//varint integers, handles and type indices are mostly small so this keeps snapshots compact
+fn SnapshotOptions() -> impl Options {
+    return bincode::DefaultOptions::new();
+}
+
+fn Links(map: &LinkMap, from: handle_uuid, type_id: TypeId) -> &[handle_uuid] {
+    return map
+        .get(&from)
+        .and_then(|type_map| type_map.get(&type_id))
+        .map(|links| links.as_slice())
+        .unwrap_or(&[]);
+}
+
+//the links of some from_type handle that hold more than one to_type entry
+fn FindCrowded(map: &LinkMap, from_type: TypeId, to_type: TypeId) -> Option<&[handle_uuid]> {
+    return map
+        .iter()
+        .filter(|(from, _)| from.type_id == from_type)
+        .filter_map(|(_, type_map)| type_map.get(&to_type))
+        .find(|links| links.len() > 1)
+        .map(|links| links.as_slice());
+}
+
+fn InsertLink(map: &mut LinkMap, from: handle_uuid, to: handle_uuid) {
+    map.entry(from)
+        .or_insert_with(|| HashMap::new())
+        .entry(to.type_id)
+        .or_insert_with(|| Vec::new())
+        .push(to);
+}
+
+//removes one from -> to entry, dropping maps left empty so they don't pile up as entities come and go
+fn RemoveLink(map: &mut LinkMap, from: handle_uuid, to: handle_uuid) -> bool {
+    let type_map = match map.get_mut(&from) {
+        Some(type_map) => type_map,
+        None => return false,
+    };
+    let links = match type_map.get_mut(&to.type_id) {
+        Some(links) => links,
+        None => return false,
+    };
+    let index = match links.iter().position(|link| *link == to) {
+        Some(index) => index,
+        None => return false,
+    };
+
+    links.swap_remove(index);
+    if links.is_empty() {
+        type_map.remove(&to.type_id);
+        if type_map.is_empty() {
+            map.remove(&from);
+        }
+    }
+    return true;
+}
+
+fn LinksOf<T: 'static, L: HandleLayout>(
+    map: &LinkMap,
+    from: handle_uuid,
+) -> Vec<handle::handle_t<T, L>> {
+    return Links(map, from, TypeId::of::<T>())
+        .iter()
+        .map(|uuid| handle::handle_t::<T, L>::new(L::Raw::FromBits(uuid.handle_value)))
+        .collect();
+}
+
+fn RemapLinks(map: &mut LinkMap, uuid_remap: &HashMap<handle_uuid, handle_uuid>, type_id: TypeId) {
+    //pull every moved entry out before reinserting, a new handle can be another entry's old one
+    let moved: Vec<handle_uuid> = map
+        .keys()
+        .filter(|uuid| uuid_remap.contains_key(uuid))
+        .cloned()
+        .collect();
+    let moved_links: Vec<_> = moved
+        .iter()
+        .map(|old| (uuid_remap[old], map.remove(old).expect("")))
+        .collect();
+    for (new, links) in moved_links {
+        map.insert(new, links);
+    }
+
+    for type_map in map.values_mut() {
+        if let Some(links) = type_map.get_mut(&type_id) {
+            for link in links.iter_mut() {
+                if let Some(new) = uuid_remap.get(link) {
+                    *link = *new;
+                }
             }
         }
-        return Vec::new();
     }
 }
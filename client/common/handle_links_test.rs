@@ -0,0 +1,380 @@
+extern crate handle;
+extern crate handle_links;
+extern crate sparce_buffer;
+
+use handle::handle_t;
+use handle_links::{handle_uuid, Cardinality, LinkError, LinkTable, SnapshotError};
+use sparce_buffer::SparceBuffer;
+
+struct Creature;
+struct Drawable;
+struct Transform;
+
+fn Handle<T>(instance: usize) -> handle_t<T> {
+    return handle_t::from(1, 0, instance);
+}
+
+#[test]
+fn unlink_and_reverse_lookup() {
+    let mut links = LinkTable::new();
+    let transform = Handle::<Transform>(0);
+    let drawables: Vec<handle_t<Drawable>> = (0..3).map(Handle).collect();
+    for drawable in drawables.iter() {
+        links.Link(*drawable, transform).unwrap();
+    }
+
+    let mut linking = links.GetLinkingHandles::<Drawable, Transform>(transform);
+    linking.sort();
+    assert_eq!(linking, drawables);
+
+    assert!(links.Unlink(drawables[1], transform));
+    assert!(!links.Unlink(drawables[1], transform));
+    assert!(links
+        .GetLinkedHandles::<Drawable, Transform>(drawables[1])
+        .is_empty());
+    let mut linking = links.GetLinkingHandles::<Drawable, Transform>(transform);
+    linking.sort();
+    assert_eq!(linking, vec![drawables[0], drawables[2]]);
+
+    //the same bits under another type are a different handle
+    assert!(links
+        .GetLinkingHandles::<Creature, Transform>(transform)
+        .is_empty());
+}
+
+#[test]
+fn remove_all_cuts_both_directions() {
+    let mut links = LinkTable::new();
+    let creature = Handle::<Creature>(0);
+    let drawable = Handle::<Drawable>(0);
+    let transform = Handle::<Transform>(0);
+    links.Link(creature, drawable).unwrap();
+    links.Link(drawable, transform).unwrap();
+
+    assert!(links.RemoveAll(drawable, false).is_empty());
+    assert!(links
+        .GetLinkedHandles::<Creature, Drawable>(creature)
+        .is_empty());
+    assert!(links
+        .GetLinkingHandles::<Drawable, Transform>(transform)
+        .is_empty());
+}
+
+//a creature with a drawable and a transform, the drawable also points at the transform
+fn Despawnable(
+    links: &mut LinkTable,
+) -> (handle_t<Creature>, handle_t<Drawable>, handle_t<Transform>) {
+    let creature = Handle::<Creature>(0);
+    let drawable = Handle::<Drawable>(0);
+    let transform = Handle::<Transform>(0);
+    links.Link(creature, drawable).unwrap();
+    links.Link(creature, transform).unwrap();
+    links.Link(drawable, transform).unwrap();
+    //a link back to the creature must not send the cascade around in circles
+    links.Link(transform, creature).unwrap();
+    return (creature, drawable, transform);
+}
+
+#[test]
+fn remove_all_cascades_to_linked_handles() {
+    let mut links = LinkTable::new();
+    let (creature, drawable, transform) = Despawnable(&mut links);
+
+    let removed = links.RemoveAll(creature, true);
+    assert_eq!(removed.len(), 2);
+    assert!(removed
+        .iter()
+        .any(|uuid| uuid.Downcast::<Drawable, handle::Handle32>() == Some(drawable)));
+    assert!(removed
+        .iter()
+        .any(|uuid| uuid.Downcast::<Transform, handle::Handle32>() == Some(transform)));
+    assert!(links.Iter().next().is_none());
+}
+
+#[test]
+fn remove_all_keeps_shared_targets() {
+    let mut links = LinkTable::new();
+    let (creature, drawable, transform) = Despawnable(&mut links);
+    //another creature follows the despawned one's transform
+    let follower = Handle::<Creature>(1);
+    links.Link(follower, transform).unwrap();
+
+    let removed = links.RemoveAll(creature, true);
+    assert_eq!(
+        removed
+            .iter()
+            .map(|uuid| uuid.Downcast::<Drawable, handle::Handle32>())
+            .collect::<Vec<_>>(),
+        vec![Some(drawable)]
+    );
+    assert_eq!(
+        links.GetLinkedHandles::<Creature, Transform>(follower),
+        vec![transform]
+    );
+    assert_eq!(
+        links.GetLinkingHandles::<Creature, Transform>(transform),
+        vec![follower]
+    );
+    assert!(links
+        .GetLinkingHandles::<Drawable, Transform>(transform)
+        .is_empty());
+    assert!(links
+        .GetLinkedHandles::<Transform, Creature>(transform)
+        .is_empty());
+}
+
+#[test]
+fn one_to_one_links_are_enforced() {
+    let mut links = LinkTable::new();
+    links
+        .Declare::<Drawable, Transform>(Cardinality::OneToOne)
+        .unwrap();
+    let drawable = Handle::<Drawable>(0);
+    let other_drawable = Handle::<Drawable>(1);
+    let transform = Handle::<Transform>(0);
+    let other_transform = Handle::<Transform>(1);
+
+    links.Link(drawable, transform).unwrap();
+    assert_eq!(
+        links.Link(drawable, transform),
+        Err(LinkError::AlreadyLinked)
+    );
+    assert_eq!(
+        links.Link(drawable, other_transform),
+        Err(LinkError::TargetTaken(handle_uuid::From(transform)))
+    );
+    assert_eq!(
+        links.Link(other_drawable, transform),
+        Err(LinkError::SourceTaken(handle_uuid::From(drawable)))
+    );
+
+    assert_eq!(
+        links.GetLinked::<Drawable, Transform>(drawable),
+        Some(transform)
+    );
+    assert_eq!(
+        links.GetLinking::<Drawable, Transform>(transform),
+        Some(drawable)
+    );
+    assert_eq!(links.GetLinked::<Drawable, Transform>(other_drawable), None);
+
+    //once unlinked the transform is free for another drawable
+    links.Unlink(drawable, transform);
+    links.Link(other_drawable, transform).unwrap();
+}
+
+#[test]
+fn single_lookups_on_undeclared_pairs() {
+    let mut links = LinkTable::new();
+    let creature = Handle::<Creature>(0);
+    let drawable = Handle::<Drawable>(0);
+
+    //nothing declared for the pair, a lookup is still a valid query
+    assert_eq!(links.GetLinked::<Creature, Drawable>(creature), None);
+    assert_eq!(links.GetLinking::<Creature, Drawable>(drawable), None);
+
+    links.Link(creature, drawable).unwrap();
+    assert_eq!(
+        links.GetLinked::<Creature, Drawable>(creature),
+        Some(drawable)
+    );
+    assert_eq!(
+        links.GetLinking::<Creature, Drawable>(drawable),
+        Some(creature)
+    );
+}
+
+#[test]
+fn one_to_many_and_redeclaring() {
+    let mut links = LinkTable::new();
+    let creature = Handle::<Creature>(0);
+    let other_creature = Handle::<Creature>(1);
+    let parts: Vec<handle_t<Drawable>> = (0..3).map(Handle).collect();
+    for part in parts.iter() {
+        links.Link(creature, *part).unwrap();
+    }
+
+    //the table already has a creature with three drawables
+    assert_eq!(
+        links.Declare::<Creature, Drawable>(Cardinality::OneToOne),
+        Err(LinkError::TargetTaken(handle_uuid::From(parts[0])))
+    );
+    assert_eq!(
+        links.CardinalityOf::<Creature, Drawable>(),
+        Cardinality::ManyToMany
+    );
+
+    links
+        .Declare::<Creature, Drawable>(Cardinality::OneToMany)
+        .unwrap();
+    assert_eq!(
+        links.Link(other_creature, parts[1]),
+        Err(LinkError::SourceTaken(handle_uuid::From(creature)))
+    );
+    assert_eq!(
+        links.GetLinking::<Creature, Drawable>(parts[2]),
+        Some(creature)
+    );
+    assert_eq!(
+        links.GetLinkedHandles::<Creature, Drawable>(creature).len(),
+        3
+    );
+}
+
+#[test]
+fn join_resolves_linked_components() {
+    let drawables = SparceBuffer::<u32>::new();
+    let mut transforms = SparceBuffer::<f32>::new();
+    let names = SparceBuffer::<String>::new();
+    let mut links = LinkTable::new();
+
+    //every drawable gets a transform, every third also gets a name
+    let mut expected = Vec::new();
+    for index in 0..30u32 {
+        let drawable = drawables.Allocate(index);
+        if index % 5 == 0 {
+            continue;
+        }
+        let transform = transforms.Allocate(index as f32 * 0.5);
+        links.Link(drawable, transform).unwrap();
+        if index % 3 == 0 {
+            links
+                .Link(drawable, names.Allocate(format!("drawable {}", index)))
+                .unwrap();
+            expected.push(index);
+        }
+    }
+
+    let joined: Vec<u32> = links
+        .Join(&drawables, &transforms)
+        .map(|(h, drawable, transform)| {
+            assert_eq!(drawables[h], *drawable);
+            assert_eq!(*transform, *drawable as f32 * 0.5);
+            return *drawable;
+        })
+        .collect();
+    assert_eq!(joined.len(), 24);
+
+    let mut joined: Vec<u32> = links
+        .Join3(&drawables, &transforms, &names)
+        .map(|(_, drawable, _, name)| {
+            assert_eq!(*name, format!("drawable {}", drawable));
+            return *drawable;
+        })
+        .collect();
+    joined.sort();
+    assert_eq!(joined, expected);
+
+    //a link into a freed slot no longer joins
+    let (first, _, transform) = links.Join(&drawables, &transforms).next().unwrap();
+    let transform_handle = links.GetLinkedHandles::<u32, f32>(first)[0];
+    assert_eq!(transforms[transform_handle], *transform);
+    transforms.Free(transform_handle);
+    assert_eq!(links.Join(&drawables, &transforms).count(), 23);
+}
+
+fn NamedTable() -> LinkTable {
+    let mut links = LinkTable::new();
+    links.RegisterType::<Creature>("creature");
+    links.RegisterType::<Drawable>("drawable");
+    links.RegisterType::<Transform>("transform");
+    return links;
+}
+
+#[test]
+fn snapshot_round_trips_links() {
+    let mut links = NamedTable();
+    links
+        .Declare::<Drawable, Transform>(Cardinality::OneToOne)
+        .unwrap();
+    for index in 0..40 {
+        let creature = Handle::<Creature>(index % 10);
+        let drawable = handle_t::<Drawable>::from(2, index, 3);
+        links.Link(creature, drawable).unwrap();
+        links
+            .Link(drawable, handle_t::<Transform>::from(5, index, 7))
+            .unwrap();
+    }
+    let bytes = links.Snapshot().unwrap();
+    //80 links in less than two fixed u64s each
+    assert!(bytes.len() < 80 * 16, "{} bytes", bytes.len());
+    assert_eq!(links.Snapshot().unwrap(), bytes);
+
+    //the loading side registers in another order, only the names have to match
+    let mut loaded = LinkTable::new();
+    loaded.RegisterType::<Transform>("transform");
+    loaded.RegisterType::<Drawable>("drawable");
+    loaded.RegisterType::<Creature>("creature");
+    loaded.Restore(&bytes).unwrap();
+    assert_eq!(loaded.Snapshot().unwrap(), bytes);
+
+    let mut expected: Vec<_> = links.Iter().collect();
+    let mut restored: Vec<_> = loaded.Iter().collect();
+    expected.sort();
+    restored.sort();
+    assert_eq!(restored, expected);
+    let mut drawables = loaded.GetLinkedHandles::<Creature, Drawable>(Handle(3));
+    drawables.sort();
+    assert_eq!(
+        drawables,
+        (0..4)
+            .map(|n| handle_t::from(2, 3 + n * 10, 3))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        loaded.GetLinkingHandles::<Drawable, Transform>(handle_t::from(5, 17, 7)),
+        vec![handle_t::from(2, 17, 3)]
+    );
+}
+
+#[test]
+fn restore_rejects_what_this_table_cant_hold() {
+    let mut links = NamedTable();
+    let transform = Handle::<Transform>(0);
+    links.Link(Handle::<Drawable>(0), transform).unwrap();
+    links.Link(Handle::<Drawable>(1), transform).unwrap();
+    let bytes = links.Snapshot().unwrap();
+
+    //the loading side has no drawable type
+    let mut unnamed = LinkTable::<handle::Handle32>::new();
+    unnamed.RegisterType::<Transform>("transform");
+    assert!(matches!(
+        unnamed.Restore(&bytes),
+        Err(SnapshotError::Corrupt(_))
+    ));
+
+    //two drawables share a transform, which this table forbids
+    let mut strict = NamedTable();
+    strict
+        .Declare::<Drawable, Transform>(Cardinality::OneToOne)
+        .unwrap();
+    let kept = Handle::<Creature>(5);
+    strict.Link(kept, Handle::<Drawable>(9)).unwrap();
+    assert!(matches!(
+        strict.Restore(&bytes),
+        Err(SnapshotError::Corrupt(_))
+    ));
+    assert_eq!(
+        strict.GetLinkedHandles::<Creature, Drawable>(kept),
+        vec![Handle(9)]
+    );
+
+    assert!(matches!(
+        strict.Restore(&bytes[..bytes.len() - 1]),
+        Err(SnapshotError::Encoding(_))
+    ));
+
+    let mut anonymous = LinkTable::new();
+    anonymous.Link(Handle::<Creature>(0), transform).unwrap();
+    assert!(matches!(
+        anonymous.Snapshot(),
+        Err(SnapshotError::UnnamedType(_))
+    ));
+}
+
+#[test]
+#[should_panic(expected = "already taken")]
+fn type_names_are_unique() {
+    let mut links = NamedTable();
+    links.RegisterType::<u32>("creature");
+}
@@ -0,0 +1,52 @@
+extern crate handle;
+
+use handle::{handle_t, Handle32, Handle64, HandleLayout};
+
+struct Creature;
+
+//packs then unpacks every field at its largest value and at zero
+fn RoundTrip<L: HandleLayout>() {
+    let max_node = (L::MAX_NODES - 1) as usize;
+    let max_instance = (1usize << L::INSTANCE_BITS) - 1;
+
+    let h = handle_t::<Creature, L>::from(L::MAX_GENERATION, max_node, max_instance);
+    assert_eq!(h.Generation(), L::MAX_GENERATION);
+    assert_eq!(h.Node(), max_node);
+    assert_eq!(h.Instance(), max_instance);
+    assert!(!h.IsNull());
+
+    //fields don't bleed into their neighbours
+    let h = handle_t::<Creature, L>::from(1, max_node, 0);
+    assert_eq!((h.Generation(), h.Node(), h.Instance()), (1, max_node, 0));
+    let h = handle_t::<Creature, L>::from(L::MAX_GENERATION, 0, max_instance);
+    assert_eq!(
+        (h.Generation(), h.Node(), h.Instance()),
+        (L::MAX_GENERATION, 0, max_instance)
+    );
+
+    let h = handle_t::<Creature, L>::new(h.Value());
+    assert_eq!(h.Generation(), L::MAX_GENERATION);
+}
+
+#[test]
+fn handle32_round_trips() {
+    RoundTrip::<Handle32>();
+}
+
+#[test]
+fn handle64_round_trips() {
+    RoundTrip::<Handle64>();
+    assert_eq!(Handle64::MAX_GENERATION, (1 << 24) - 1);
+    assert_eq!(Handle64::MAX_NODES, 1 << 34);
+
+    //every bit of the u64 is used
+    let h = handle_t::<Creature, Handle64>::from(Handle64::MAX_GENERATION, (1 << 34) - 1, 63);
+    assert_eq!(h.Value(), u64::MAX);
+}
+
+#[test]
+fn generations_wrap_past_zero() {
+    assert_eq!(Handle64::NextGeneration(Handle64::MAX_GENERATION), 1);
+    assert_eq!(Handle64::NextGeneration(1), 2);
+    assert_eq!(Handle32::NextGeneration(255), 1);
+}
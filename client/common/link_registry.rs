@@ -0,0 +1,227 @@
+extern crate handle;
+extern crate handle_links;
+extern crate sparce_buffer;
+extern crate sparce_buffer_rc;
+use handle::HandleLayout;
+use handle_links::{handle_uuid, LinkTable};
+use sparce_buffer::SparceBuffer;
+use sparce_buffer_rc::{BufferRef, BufferRefMut, SparceBufferRc};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+/*
+ * What the registry needs from a buffer without knowing its value type
+ */
+trait RegisteredBuffer<L: HandleLayout> {
+    //true if uuid is a handle to a live slot of this buffer
+    fn IsLive(&self, uuid: handle_uuid) -> bool;
+    //gives up the registry's claim on the slot, returns true if the slot was reclaimed
+    fn Release(&mut self, uuid: handle_uuid) -> bool;
+    fn AsAny(&self) -> &dyn Any;
+    fn AsAnyMut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: 'static, L: HandleLayout> RegisteredBuffer<L> for SparceBuffer<T, L> {
+    fn IsLive(&self, uuid: handle_uuid) -> bool {
+        return match uuid.Downcast::<T, L>() {
+            Some(h) => self.IsValid(h),
+            None => false,
+        };
+    }
+
+    fn Release(&mut self, uuid: handle_uuid) -> bool {
+        return self.Remove(uuid.Downcast::<T, L>().expect("")).is_some();
+    }
+
+    fn AsAny(&self) -> &dyn Any {
+        return self;
+    }
+
+    fn AsAnyMut(&mut self) -> &mut dyn Any {
+        return self;
+    }
+}
+
+impl<T: 'static, L: HandleLayout> RegisteredBuffer<L> for SparceBufferRc<T, L> {
+    fn IsLive(&self, uuid: handle_uuid) -> bool {
+        return match uuid.Downcast::<T, L>() {
+            Some(h) => self.IsAlive(h),
+            None => false,
+        };
+    }
+
+    //only the last reference reclaims the slot, the links stay until then
+    fn Release(&mut self, uuid: handle_uuid) -> bool {
+        return self.Unref(uuid.Downcast::<T, L>().expect("")).is_some();
+    }
+
+    fn AsAny(&self) -> &dyn Any {
+        return self;
+    }
+
+    fn AsAnyMut(&mut self) -> &mut dyn Any {
+        return self;
+    }
+}
+
+/*
+ * Owns the buffers of the linked types together with their LinkTable
+ *
+ * Slots freed through the registry take their links with them, in both directions,
+ * so a reused slot never inherits the links of whatever lived there before
+ * Nothing the registry hands out can free a slot behind its back, so a link only goes
+ * stale when a handle is linked after it was freed, StaleLinks and DebugCheck catch that
+ */
+pub struct LinkRegistry<L: HandleLayout = handle::Handle32> {
+    links: LinkTable<L>,
+    buffers: HashMap<TypeId, Box<dyn RegisteredBuffer<L>>>,
+}
+
+impl<L: HandleLayout> LinkRegistry<L> {
+    pub fn new() -> Self {
+        return Self {
+            links: LinkTable::new(),
+            buffers: HashMap::new(),
+        };
+    }
+
+    /*
+     * Hands the buffer for T to the registry, replacing any buffer T already had
+     */
+    pub fn Register<T: 'static>(&mut self, buffer: SparceBuffer<T, L>) {
+        self.buffers.insert(TypeId::of::<T>(), Box::new(buffer));
+    }
+
+    pub fn RegisterRc<T: 'static>(&mut self, buffer: SparceBufferRc<T, L>) {
+        self.buffers.insert(TypeId::of::<T>(), Box::new(buffer));
+    }
+
+    /*
+     * Read only view of T's buffer for iterating, freeing goes through Free
+     * None if T has no buffer or it was registered as a SparceBufferRc
+     */
+    pub fn Buffer<T: 'static>(&self) -> Option<&SparceBuffer<T, L>> {
+        return self.buffers.get(&TypeId::of::<T>())?.AsAny().downcast_ref();
+    }
+
+    /*
+     * Allocates into T's buffer, whichever kind it was registered as
+     */
+    pub fn Allocate<T: 'static>(&self, value: T) -> handle::handle_t<T, L> {
+        let buffer = self
+            .buffers
+            .get(&TypeId::of::<T>())
+            .expect("LinkRegistry: type was never registered")
+            .AsAny();
+        if let Some(buffer) = buffer.downcast_ref::<SparceBuffer<T, L>>() {
+            return buffer.Allocate(value);
+        }
+        return self.RcBuffer::<T>().Allocate(value);
+    }
+
+    /*
+     * None for stale handles or if T was registered as a SparceBufferRc, see GetRc
+     */
+    pub fn Get<T: 'static>(&self, h: handle::handle_t<T, L>) -> Option<&T> {
+        return self.Buffer::<T>()?.TryGet(h);
+    }
+
+    pub fn GetMut<T: 'static>(&mut self, h: handle::handle_t<T, L>) -> Option<&mut T> {
+        let buffer: &mut SparceBuffer<T, L> = self
+            .buffers
+            .get_mut(&TypeId::of::<T>())?
+            .AsAnyMut()
+            .downcast_mut()?;
+        return buffer.TryGetMut(h);
+    }
+
+    /*
+     * Guarded access to a value of a SparceBufferRc
+     * Panics if T wasn't registered with RegisterRc, or as SparceBufferRc::Get does
+     */
+    #[track_caller]
+    pub fn GetRc<T: 'static>(&self, h: handle::handle_t<T, L>) -> BufferRef<'_, T> {
+        return self.RcBuffer::<T>().Get(h);
+    }
+
+    #[track_caller]
+    pub fn GetRcMut<T: 'static>(&self, h: handle::handle_t<T, L>) -> BufferRefMut<'_, T> {
+        return self.RcBuffer::<T>().GetMut(h);
+    }
+
+    /*
+     * Takes one more reference on a SparceBufferRc slot, each one is given back with Free
+     */
+    pub fn BumpRef<T: 'static>(&self, h: handle::handle_t<T, L>) {
+        self.RcBuffer::<T>().BumpRef(h);
+    }
+
+    //kept private, its Free and Unref would release slots without dropping their links
+    #[track_caller]
+    fn RcBuffer<T: 'static>(&self) -> &SparceBufferRc<T, L> {
+        return self
+            .buffers
+            .get(&TypeId::of::<T>())
+            .and_then(|buffer| buffer.AsAny().downcast_ref())
+            .expect("LinkRegistry: type was never registered with RegisterRc");
+    }
+
+    pub fn Links(&self) -> &LinkTable<L> {
+        return &self.links;
+    }
+
+    pub fn LinksMut(&mut self) -> &mut LinkTable<L> {
+        return &mut self.links;
+    }
+
+    /*
+     * Frees h from its buffer and drops every link from and to it
+     *
+     * For a SparceBufferRc this releases one reference, the links go with the last one
+     * Returns true if the slot was reclaimed, false for stale handles and slots still referenced
+     */
+    pub fn Free<T: 'static>(&mut self, h: handle::handle_t<T, L>) -> bool {
+        let buffer = self
+            .buffers
+            .get_mut(&TypeId::of::<T>())
+            .expect("LinkRegistry: type was never registered");
+        let uuid = handle_uuid::From(h);
+        if !buffer.IsLive(uuid) || !buffer.Release(uuid) {
+            return false;
+        }
+        self.links.RemoveAll(h, false);
+        return true;
+    }
+
+    /*
+     * Every link with an end in a slot that is no longer live
+     * Ends whose type has no registered buffer can't be checked and are taken as live
+     */
+    pub fn StaleLinks(&self) -> Vec<(handle_uuid, handle_uuid)> {
+        let is_live = |uuid: handle_uuid| match self.buffers.get(&uuid.TypeId()) {
+            Some(buffer) => buffer.IsLive(uuid),
+            None => true,
+        };
+        return self
+            .links
+            .Iter()
+            .filter(|(from, to)| !is_live(*from) || !is_live(*to))
+            .collect();
+    }
+
+    /*
+     * Panics on any stale link in debug builds, does nothing in release
+     * Walks every link, so call it at a sync point rather than per entity
+     */
+    pub fn DebugCheck(&self) {
+        if cfg!(debug_assertions) {
+            let stale = self.StaleLinks();
+            assert!(
+                stale.is_empty(),
+                "LinkRegistry: {} links point at freed slots: {:?}",
+                stale.len(),
+                stale
+            );
+        }
+    }
+}
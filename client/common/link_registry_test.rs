@@ -0,0 +1,126 @@
+extern crate handle_links;
+extern crate link_registry;
+extern crate sparce_buffer;
+extern crate sparce_buffer_rc;
+
+use handle_links::handle_uuid;
+use link_registry::LinkRegistry;
+use sparce_buffer::SparceBuffer;
+use sparce_buffer_rc::SparceBufferRc;
+
+struct Creature {
+    hp: u32,
+}
+
+struct Drawable {
+    mesh: u32,
+}
+
+struct Transform {
+    x: f32,
+}
+
+fn World() -> LinkRegistry {
+    let mut world = LinkRegistry::new();
+    world.Register(SparceBuffer::<Creature>::new());
+    world.Register(SparceBuffer::<Drawable>::new());
+    world.RegisterRc(SparceBufferRc::<Transform>::new());
+    return world;
+}
+
+#[test]
+fn free_drops_links_both_ways() {
+    let mut world = World();
+    let creature = world.Allocate(Creature { hp: 10 });
+    let drawable = world.Allocate(Drawable { mesh: 3 });
+    let transform = world.Allocate(Transform { x: 1.0 });
+    world.LinksMut().Link(creature, drawable).unwrap();
+    world.LinksMut().Link(drawable, transform).unwrap();
+    assert!(world.Buffer::<Drawable>().is_some() && world.Buffer::<Transform>().is_none());
+
+    assert!(world.Free(drawable));
+    assert!(!world.Free(drawable));
+    assert!(world
+        .Links()
+        .GetLinkedHandles::<Creature, Drawable>(creature)
+        .is_empty());
+    assert!(world
+        .Links()
+        .GetLinkingHandles::<Drawable, Transform>(transform)
+        .is_empty());
+
+    //the reused slot starts without links
+    let reused = world.Allocate(Drawable { mesh: 4 });
+    assert_eq!(reused.Node(), drawable.Node());
+    assert_eq!(reused.Instance(), drawable.Instance());
+    assert!(world
+        .Links()
+        .GetLinkedHandles::<Drawable, Transform>(reused)
+        .is_empty());
+    assert_eq!(world.Get(creature).unwrap().hp, 10);
+    assert_eq!(world.Get(reused).unwrap().mesh, 4);
+    assert!(world.Get(drawable).is_none());
+    world.DebugCheck();
+}
+
+#[test]
+fn rc_links_go_with_the_last_reference() {
+    let mut world = World();
+    let drawable = world.Allocate(Drawable { mesh: 0 });
+    let transform = world.Allocate(Transform { x: 2.0 });
+    world.BumpRef(transform);
+    world.LinksMut().Link(drawable, transform).unwrap();
+
+    assert!(!world.Free(transform));
+    assert_eq!(
+        world
+            .Links()
+            .GetLinkedHandles::<Drawable, Transform>(drawable),
+        vec![transform]
+    );
+    assert_eq!(world.GetRc(transform).x, 2.0);
+
+    assert!(world.Free(transform));
+    assert!(world
+        .Links()
+        .GetLinkedHandles::<Drawable, Transform>(drawable)
+        .is_empty());
+    world.DebugCheck();
+}
+
+#[test]
+fn stale_links_are_flagged() {
+    let mut world = World();
+    let creature = world.Allocate(Creature { hp: 1 });
+    let drawable = world.Allocate(Drawable { mesh: 1 });
+    world.LinksMut().Link(creature, drawable).unwrap();
+    assert!(world.StaleLinks().is_empty());
+
+    //a handle kept around after it was freed and linked anyway
+    assert!(world.Free(drawable));
+    world.LinksMut().Link(creature, drawable).unwrap();
+    assert_eq!(
+        world.StaleLinks(),
+        vec![(handle_uuid::From(creature), handle_uuid::From(drawable))]
+    );
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| world.DebugCheck()));
+    assert_eq!(result.is_err(), cfg!(debug_assertions));
+}
+
+#[test]
+fn registered_values_can_be_changed() {
+    let mut world = World();
+    let creature = world.Allocate(Creature { hp: 10 });
+    let transform = world.Allocate(Transform { x: 0.0 });
+
+    world.GetMut(creature).unwrap().hp -= 3;
+    world.GetRcMut(transform).x = 5.0;
+    assert_eq!(world.Get(creature).unwrap().hp, 7);
+    assert_eq!(world.GetRc(transform).x, 5.0);
+    //Transform lives in a SparceBufferRc, only the guarded accessors reach it
+    assert!(world.GetMut(transform).is_none());
+
+    assert!(world.Free(creature));
+    assert!(world.GetMut(creature).is_none());
+}
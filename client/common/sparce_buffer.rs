@@ -1,171 +1,788 @@
+extern crate bincode;
 extern crate handle;
+extern crate serde;
+use handle::HandleLayout;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::cell::Cell;
 use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
 use std::mem::MaybeUninit;
 use std::ops::{Index, IndexMut};
+use std::ptr;
 
-const NULL_INDEX: u8 = 255;
+const NULL_INDEX: usize = usize::MAX;
 const NODE_SIZE: usize = 32;
-const NODE_COUNT: usize = 255;
 
 pub struct Node<T> {
     data: [MaybeUninit<T>; NODE_SIZE],
+    //generation of each slot, bumped every time the slot is freed
+    generation: [u32; NODE_SIZE],
     free: u32,
+    next: usize,
 }
 
-pub struct SparceBuffer<T> {
-    buffer: [UnsafeCell<Option<Box<Node<T>>>>; NODE_COUNT],
+/*
+ * Nodes are heap allocated so growing the vec never moves the values handles point at
+ *
+ * They are only reached through the raw pointers kept here, never through a &mut Node,
+ * so filling a free slot from &self can't invalidate a &T handed out for another slot
+ * Emptying a slot takes &mut self, which keeps every &T out of the way
+ */
+pub struct SparceBuffer<T, L: HandleLayout = handle::Handle32> {
+    nodes: UnsafeCell<Vec<*mut Node<T>>>,
+    free_list: Cell<usize>,
+    alloc_count: Cell<usize>,
+    max_nodes: usize,
+    //generation new nodes start at, see handle::CompactSlots
+    fresh_generation: u32,
+    _layout: PhantomData<L>,
+}
+
+/*
+ * Returned by TryAllocate when the buffer can't grow any further
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError {
+    pub max_nodes: usize,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "SparceBuffer is out of capacity ({} nodes of {} slots)",
+            self.max_nodes, NODE_SIZE
+        );
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+unsafe impl<T: Send, L: HandleLayout> Send for SparceBuffer<T, L> {}
+
+/*
+ * Walks the taken slots in node order, only visits nodes that already exist
+ */
+struct SlotCursor {
+    node: usize,
+    instance: usize,
+}
+
+pub struct SparceBufferIter<'a, T, L: HandleLayout = handle::Handle32> {
+    buffer: &'a SparceBuffer<T, L>,
+    cursor: SlotCursor,
+}
 
-    next: [Cell<u8>; NODE_COUNT],
-    free_list: Cell<u8>,
-    alloc_count: Cell<u8>,
+pub struct SparceBufferHandleIter<'a, T, L: HandleLayout = handle::Handle32> {
+    buffer: &'a SparceBuffer<T, L>,
+    cursor: SlotCursor,
 }
 
-pub struct SparceBufferIter<'a, T> {
-    buffer: &'a SparceBuffer<T>,
-    index: i32,
-    iter_count: i32,
+/*
+ * Only handed out from &mut SparceBuffer, so each slot is yielded mutably exactly once
+ */
+pub struct SparceBufferIterMut<'a, T, L: HandleLayout = handle::Handle32> {
+    buffer: &'a SparceBuffer<T, L>,
+    cursor: SlotCursor,
 }
 
-impl<T> SparceBuffer<T> {
+/*
+ * Frees every slot it yields, whatever is left over is freed when it is dropped
+ */
+pub struct SparceBufferDrain<'a, T, L: HandleLayout = handle::Handle32> {
+    buffer: &'a mut SparceBuffer<T, L>,
+    cursor: SlotCursor,
+}
+
+impl<T, L: HandleLayout> SparceBuffer<T, L> {
     pub fn new() -> Self {
-        let mut next: [Cell<u8>; 255] = core::array::from_fn(|i| Cell::new(i as u8));
-        for i in 0..255 {
-            next[i].set((i + 1) as u8);
-        }
-        next[254].set(NULL_INDEX);
+        return Self::WithMaxNodes(usize::MAX);
+    }
 
+    /*
+     * Caps how many nodes the buffer may grow to
+     * The cap is clamped to the number of nodes the handle layout can address
+     */
+    pub fn WithMaxNodes(max_nodes: usize) -> Self {
+        let layout_max = usize::try_from(L::MAX_NODES).unwrap_or(usize::MAX);
         return Self {
-            buffer: std::array::from_fn(|_| UnsafeCell::new(None)),
-            next: next,
-            free_list: Cell::new(0),
+            nodes: UnsafeCell::new(Vec::new()),
+            free_list: Cell::new(NULL_INDEX),
             alloc_count: Cell::new(0),
+            max_nodes: max_nodes.min(layout_max),
+            fresh_generation: 1,
+            _layout: PhantomData,
         };
     }
 
-    pub fn Iter(&self) -> SparceBufferIter<T> {
+    pub fn Iter(&self) -> SparceBufferIter<T, L> {
         return SparceBufferIter {
             buffer: self,
-            index: 0,
-            iter_count: 0,
+            cursor: SlotCursor::new(),
+        };
+    }
+
+    /*
+     * Same as Iter but also yields the handle each value lives at
+     */
+    pub fn IterWithHandles(&self) -> SparceBufferHandleIter<T, L> {
+        return SparceBufferHandleIter {
+            buffer: self,
+            cursor: SlotCursor::new(),
+        };
+    }
+
+    pub fn IterMut(&mut self) -> SparceBufferIterMut<T, L> {
+        return SparceBufferIterMut {
+            buffer: self,
+            cursor: SlotCursor::new(),
         };
     }
 
-    pub fn Allocate(&self, value: T) -> handle::handle_t<T> {
+    /*
+     * Frees every value the predicate returns false for
+     */
+    pub fn Retain<F>(&mut self, mut predicate: F)
+    where
+        F: FnMut(handle::handle_t<T, L>, &mut T) -> bool,
+    {
+        let mut cursor = SlotCursor::new();
+        while let Some((node, instance)) = cursor.Next(self) {
+            let h = self.HandleAt(node, instance);
+            //SAFETY: the slot is taken and &mut self means nothing else points into it
+            let value = unsafe { &mut *SlotPtr(self.NodePtr(node), instance) };
+            if !predicate(h, value) {
+                self.Free(h);
+            }
+        }
+    }
+
+    /*
+     * Removes every value from the buffer, yielding it along with the handle it lived at
+     */
+    pub fn Drain(&mut self) -> SparceBufferDrain<T, L> {
+        return SparceBufferDrain {
+            buffer: self,
+            cursor: SlotCursor::new(),
+        };
+    }
+
+    /*
+     * Panics once the buffer is out of capacity, see TryAllocate
+     */
+    pub fn Allocate(&self, value: T) -> handle::handle_t<T, L> {
+        return self
+            .TryAllocate(value)
+            .expect("SparceBuffer allocation failed");
+    }
+
+    pub fn TryAllocate(&self, value: T) -> Result<handle::handle_t<T, L>, AllocError> {
         if self.free_list.get() == NULL_INDEX {
-            return handle::handle_t::null();
+            self.Grow()?;
         }
-        let buffer = self.GetNode(self.free_list.get());
+        let buffer = self.NodePtr(self.free_list.get());
 
-        let leading_zero = buffer.free.leading_zeros();
-        let index = 31 - leading_zero;
-        buffer[index as usize] = value;
-        buffer.free = (buffer.free & !(1 << index));
-        self.alloc_count.set(self.alloc_count.get() + 1);
+        //SAFETY: only the node's bookkeeping and the free slot being filled are written,
+        //any &T still out points at a taken slot of this or another node
+        unsafe {
+            let leading_zero = (*buffer).free.leading_zeros();
+            let index = 31 - leading_zero;
+            SlotPtr(buffer, index as usize).write(value);
+            (*buffer).free = (*buffer).free & !(1 << index);
+            self.alloc_count.set(self.alloc_count.get() + 1);
 
-        let result = handle::handle_t::from(1, self.free_list.get() as u8, index as u8);
+            let result = handle::handle_t::from(
+                (*buffer).generation[index as usize],
+                self.free_list.get(),
+                index as usize,
+            );
 
-        if buffer.free == 0 {
-            //we are full
-            self.free_list
-                .set(self.next[self.free_list.get() as usize].get());
+            if (*buffer).free == 0 {
+                //we are full
+                self.free_list.set((*buffer).next);
+                (*buffer).next = NULL_INDEX;
+            }
+            return Ok(result);
         }
-        return result;
     }
 
     pub fn Size(&self) -> usize {
         return self.alloc_count.get() as usize;
     }
 
-    pub fn Free(&self, h: handle::handle_t<T>) {
-        if (h.IsNull()) {
-            return;
+    /*
+     * Number of nodes currently backing the buffer
+     */
+    pub fn NodeCount(&self) -> usize {
+        return self.Nodes().len();
+    }
+
+    /*
+     * Freeing a null or stale handle is a no-op
+     * The slot generation is bumped so any outstanding copies of h become stale
+     * Takes &mut self so no reference handed out by Get or Index can outlive the value
+     */
+    pub fn Free(&mut self, h: handle::handle_t<T, L>) {
+        //dropped after the slot is marked free, so a Drop impl
+        //that touches this buffer sees consistent state
+        drop(self.Remove(h));
+    }
+
+    /*
+     * Frees the slot and hands the value back instead of dropping it
+     */
+    pub fn Remove(&mut self, h: handle::handle_t<T, L>) -> Option<T> {
+        if !self.IsValid(h) {
+            return None;
+        }
+        //SAFETY: &mut self means no reference into the node is still out
+        let node = unsafe { &mut *self.NodePtr(h.Node()) };
+        //a full node was dropped from the free list, put it back
+        if node.free == 0 {
+            node.next = self.free_list.get();
+            self.free_list.set(h.Node());
         }
-        self.free_list.set(h.Node());
-        self.next[h.Node() as usize].set(self.free_list.get());
-        let mut node = self.GetNode(h.Node());
         node.free = node.free | (1 << h.Instance());
+        node.generation[h.Instance()] = L::NextGeneration(node.generation[h.Instance()]);
+        let value = unsafe { node.data[h.Instance()].assume_init_read() };
 
         self.alloc_count.set(self.alloc_count.get() - 1);
+        return Some(value);
     }
 
-    fn GetNode(&self, node: u8) -> &mut Node<T> {
-        let mut optional_box = unsafe { &mut *self.buffer[node as usize].get() };
-        if optional_box.is_none() {
-            // 1 is free 0 is used
-            *optional_box = Some(Box::new(Node::<T> {
-                data: unsafe { MaybeUninit::uninit().assume_init() },
-                free: 0xffffffff as u32,
-            }));
+    /*
+     * Returns true if h points at a live slot allocated with the same generation
+     */
+    pub fn IsValid(&self, h: handle::handle_t<T, L>) -> bool {
+        return self.FindNode(h).is_some();
+    }
+
+    /*
+     * Returns None for null or stale handles instead of reading a dead slot
+     */
+    pub fn TryGet(&self, h: handle::handle_t<T, L>) -> Option<&T> {
+        let node = self.FindNode(h)?;
+        //SAFETY: the slot is taken and only &mut self methods empty or move a slot,
+        //so it stays put for as long as &self is borrowed
+        return Some(unsafe { &*SlotPtr(node, h.Instance()) });
+    }
+
+    pub fn TryGetMut(&mut self, h: handle::handle_t<T, L>) -> Option<&mut T> {
+        let node = self.FindNode(h)?;
+        //SAFETY: the slot is taken and &mut self means nothing else points into it
+        return Some(unsafe { &mut *SlotPtr(node, h.Instance()) });
+    }
+
+    /*
+     * Looks up the node backing h without allocating it
+     * Returns None unless the slot is taken and its generation matches the handle
+     */
+    fn FindNode(&self, h: handle::handle_t<T, L>) -> Option<*mut Node<T>> {
+        if h.IsNull() {
+            return None;
+        }
+        let node = *self.Nodes().get(h.Node())?;
+        let instance = h.Instance();
+        //SAFETY: the bookkeeping fields are only read and written in place, never borrowed
+        let (free, generation) = unsafe { ((*node).free, (*node).generation[instance]) };
+        if (free & (1 << instance)) != 0 {
+            return None;
+        }
+        if generation != h.Generation() {
+            return None;
+        }
+        return Some(node);
+    }
+
+    /*
+     * Appends an empty node and pushes it onto the free list
+     */
+    fn Grow(&self) -> Result<(), AllocError> {
+        //SAFETY: Nodes and Grow are the only borrows of the vec and neither outlives its call
+        let nodes = unsafe { &mut *self.nodes.get() };
+        if nodes.len() >= self.max_nodes {
+            return Err(AllocError {
+                max_nodes: self.max_nodes,
+            });
         }
-        return optional_box.as_deref_mut().expect("");
+        // 1 is free 0 is used
+        nodes.push(Box::into_raw(Box::new(Node::<T> {
+            data: unsafe { MaybeUninit::uninit().assume_init() },
+            generation: [self.fresh_generation; NODE_SIZE],
+            free: 0xffffffff as u32,
+            next: self.free_list.get(),
+        })));
+        self.free_list.set(nodes.len() - 1);
+        return Ok(());
+    }
+
+    /*
+     * Reports how full each node is, see BufferStats::Fragmentation
+     */
+    pub fn Stats(&self) -> handle::BufferStats {
+        return handle::BufferStats::FromOccupancy(
+            NODE_SIZE,
+            self.Nodes()
+                .iter()
+                //SAFETY: reads bookkeeping in place, see FindNode
+                .map(|node| unsafe { (!(**node).free).count_ones() as usize })
+                .collect(),
+        );
+    }
+
+    /*
+     * Packs the values into as few nodes as possible, see handle::CompactSlots
+     * Moved values get a new handle, the returned table maps old to new
+     */
+    pub fn Compact(&mut self) -> handle::HandleRemap<T, L> {
+        let live = self.Size();
+        return handle::CompactSlots(&mut Compaction(self), live);
+    }
+
+    //slot is the linear index node * NODE_SIZE + instance
+    fn IsTaken(&self, slot: usize) -> bool {
+        //SAFETY: reads bookkeeping in place, see FindNode
+        let free = unsafe { (*self.NodePtr(slot / NODE_SIZE)).free };
+        return (free & (1 << (slot % NODE_SIZE))) == 0;
+    }
+
+    fn HandleAt(&self, node: usize, instance: usize) -> handle::handle_t<T, L> {
+        //SAFETY: reads bookkeeping in place, see FindNode
+        let generation = unsafe { (*self.NodePtr(node)).generation[instance] };
+        return handle::handle_t::from(generation, node, instance);
+    }
+
+    fn Nodes(&self) -> &Vec<*mut Node<T>> {
+        //SAFETY: Grow only borrows the vec mutably for the length of the call
+        return unsafe { &*self.nodes.get() };
+    }
+
+    fn NodePtr(&self, node: usize) -> *mut Node<T> {
+        return self.Nodes()[node];
     }
 }
 
-impl<'a, T> Iterator for SparceBufferIter<'a, T> {
-    type Item = &'a T;
+//what handle::CompactSlots works through, kept private so only Compact moves values
+struct Compaction<'a, T, L: HandleLayout>(&'a mut SparceBuffer<T, L>);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        while true {
-            //we already iterated all the nodes
-            if self.iter_count >= self.buffer.Size() as i32 {
-                return None;
+impl<'a, T, L: HandleLayout> handle::NodeSlots<T, L> for Compaction<'a, T, L> {
+    const NODE_SIZE: usize = NODE_SIZE;
+
+    fn NodeCount(&self) -> usize {
+        return self.0.NodeCount();
+    }
+
+    fn IsTaken(&self, slot: usize) -> bool {
+        return self.0.IsTaken(slot);
+    }
+
+    fn HandleAt(&self, slot: usize) -> handle::handle_t<T, L> {
+        return self.0.HandleAt(slot / NODE_SIZE, slot % NODE_SIZE);
+    }
+
+    fn MoveSlot(&mut self, from: usize, to: usize) {
+        let old = self.HandleAt(from);
+        let value = self.0.Remove(old).expect("taken slot");
+        //SAFETY: &mut self means no reference into the node is still out
+        let node = unsafe { &mut *self.0.NodePtr(to / NODE_SIZE) };
+        node.data[to % NODE_SIZE].write(value);
+        node.free = node.free & !(1 << (to % NODE_SIZE));
+        self.0.alloc_count.set(self.0.alloc_count.get() + 1);
+    }
+
+    fn PopNode(&mut self) -> u32 {
+        let node = self.0.nodes.get_mut().pop().expect("no node to pop");
+        //SAFETY: the node came from Box::into_raw in Grow or FromImage and every value
+        //was moved out of it
+        let node = unsafe { Box::from_raw(node) };
+        return *node.generation.iter().max().expect("");
+    }
+
+    fn RaiseFreshGeneration(&mut self, generation: u32) {
+        self.0.fresh_generation = self.0.fresh_generation.max(generation);
+    }
+
+    fn HasRoom(&self, node: usize) -> bool {
+        //SAFETY: reads bookkeeping in place, see FindNode
+        return unsafe { (*self.0.NodePtr(node)).free } != 0;
+    }
+
+    fn SetNextFree(&mut self, node: usize, next: Option<usize>) {
+        //SAFETY: &mut self means no reference into the node is still out
+        unsafe { (*self.0.NodePtr(node)).next = next.unwrap_or(NULL_INDEX) };
+    }
+
+    fn SetFreeHead(&mut self, head: Option<usize>) {
+        self.0.free_list.set(head.unwrap_or(NULL_INDEX));
+    }
+}
+
+//points at a slot without borrowing the node, so no reference to any other slot is disturbed
+fn SlotPtr<T>(node: *mut Node<T>, instance: usize) -> *mut T {
+    //SAFETY: node is a live node of the buffer, addr_of_mut only computes the address
+    return unsafe { ptr::addr_of_mut!((*node).data[instance]) as *mut T };
+}
+
+/*
+ * Serialized form of a SparceBuffer
+ *
+ * Every node is written out with its free mask, free list link and slot generations,
+ * so a restored buffer resolves every handle the original handed out
+ * V is &T when writing and T when reading, values are in slot order of the taken slots
+ * Public so tools can inspect a snapshot, Restore checks everything in it
+ * Node indices are u64::MAX for none
+ */
+#[derive(Serialize, Deserialize)]
+pub struct BufferImage<V> {
+    pub layout: (u32, u32, u32),
+    pub max_nodes: u64,
+    pub fresh_generation: u32,
+    pub free_list: u64,
+    pub alloc_count: u64,
+    pub nodes: Vec<NodeImage<V>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NodeImage<V> {
+    pub generation: Vec<u32>,
+    //1 is free 0 is taken, one bit per slot
+    pub free: u32,
+    pub next: u64,
+    pub values: Vec<V>,
+}
+
+impl<V: DeserializeOwned> BufferImage<V> {
+    /*
+     * Decodes Snapshot bytes without checking them, see Restore
+     */
+    pub fn Decode(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        return Ok(bincode::deserialize(bytes)?);
+    }
+}
+
+impl<V: Serialize> BufferImage<V> {
+    pub fn Encode(&self) -> Result<Vec<u8>, SnapshotError> {
+        return Ok(bincode::serialize(self)?);
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Encoding(bincode::Error),
+    //the bytes decoded but describe a buffer that can't exist
+    Corrupt(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            SnapshotError::Encoding(err) => write!(f, "SparceBuffer snapshot encoding: {}", err),
+            SnapshotError::Corrupt(reason) => {
+                write!(f, "SparceBuffer snapshot corrupt: {}", reason)
             }
+        };
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(err: bincode::Error) -> Self {
+        return SnapshotError::Encoding(err);
+    }
+}
+
+fn LayoutBits<L: HandleLayout>() -> (u32, u32, u32) {
+    return (L::GENERATION_BITS, L::NODE_BITS, L::INSTANCE_BITS);
+}
+
+fn EncodeIndex(index: usize) -> u64 {
+    if index == NULL_INDEX {
+        return u64::MAX;
+    }
+    return index as u64;
+}
+
+//0 is what null handles carry, past the max doesn't fit in a handle
+fn CheckGenerations<L: HandleLayout>(generations: &[u32]) -> Result<(), String> {
+    for generation in generations {
+        if *generation == 0 || *generation > L::MAX_GENERATION {
+            return Err(format!(
+                "generation {} outside 1..={}",
+                generation,
+                L::MAX_GENERATION
+            ));
+        }
+    }
+    return Ok(());
+}
 
-            let node = self.index as usize / NODE_SIZE;
-            let instance = self.index as usize % NODE_SIZE;
-            //made it to the end
-            if node >= NODE_COUNT {
-                return None;
+fn DecodeIndex(index: u64, node_count: usize) -> Result<usize, String> {
+    if index == u64::MAX {
+        return Ok(NULL_INDEX);
+    }
+    if index >= node_count as u64 {
+        return Err(format!("node index {} out of {} nodes", index, node_count));
+    }
+    return Ok(index as usize);
+}
+
+impl<T: Serialize, L: HandleLayout> SparceBuffer<T, L> {
+    /*
+     * Encodes the whole buffer, see Restore
+     */
+    pub fn Snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+        return Ok(bincode::serialize(self)?);
+    }
+
+    fn Image(&self) -> BufferImage<&T> {
+        //SAFETY: the image only reads, and the &T it holds are tied to &self like TryGet's
+        let nodes: Vec<&Node<T>> = self.Nodes().iter().map(|node| unsafe { &**node }).collect();
+        return BufferImage {
+            layout: LayoutBits::<L>(),
+            max_nodes: self.max_nodes as u64,
+            fresh_generation: self.fresh_generation,
+            free_list: EncodeIndex(self.free_list.get()),
+            alloc_count: self.alloc_count.get() as u64,
+            nodes: nodes
+                .iter()
+                .map(|node| NodeImage {
+                    generation: node.generation.to_vec(),
+                    free: node.free,
+                    next: EncodeIndex(node.next),
+                    values: (0..NODE_SIZE)
+                        .filter(|instance| (node.free & (1 << instance)) == 0)
+                        .map(|instance| unsafe { node.data[instance].assume_init_ref() })
+                        .collect(),
+                })
+                .collect(),
+        };
+    }
+}
+
+impl<T: DeserializeOwned, L: HandleLayout> SparceBuffer<T, L> {
+    /*
+     * Rebuilds a buffer from Snapshot bytes
+     * Handles issued before the snapshot resolve to the same values, freed ones stay stale
+     */
+    pub fn Restore(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let image = BufferImage::<T>::Decode(bytes)?;
+        return Self::FromImage(image).map_err(SnapshotError::Corrupt);
+    }
+
+    fn FromImage(image: BufferImage<T>) -> Result<Self, String> {
+        if image.layout != LayoutBits::<L>() {
+            return Err(format!(
+                "snapshot was taken with handle layout {:?}, expected {:?}",
+                image.layout,
+                LayoutBits::<L>()
+            ));
+        }
+        let node_count = image.nodes.len();
+        let mut result = Self::WithMaxNodes(usize::try_from(image.max_nodes).unwrap_or(usize::MAX));
+        if node_count > result.max_nodes {
+            return Err(format!(
+                "{} nodes exceeds the cap of {}",
+                node_count, result.max_nodes
+            ));
+        }
+
+        let mut alloc_count = 0;
+        for node_image in image.nodes {
+            if node_image.generation.len() != NODE_SIZE {
+                return Err(format!(
+                    "node has {} generations",
+                    node_image.generation.len()
+                ));
+            }
+            CheckGenerations::<L>(&node_image.generation)?;
+            let taken = (!node_image.free).count_ones() as usize;
+            if node_image.values.len() != taken {
+                return Err(format!(
+                    "node has {} taken slots but {} values",
+                    taken,
+                    node_image.values.len()
+                ));
             }
-            self.index = self.index + 1;
 
-            let instance_mask = 1 << instance;
-            if (self.buffer.GetNode(node as u8).free & instance_mask) == 0 {
-                self.iter_count = self.iter_count + 1;
-                return Some(&self.buffer.GetNode(node as u8)[instance]);
+            let mut node = Box::new(Node::<T> {
+                data: unsafe { MaybeUninit::uninit().assume_init() },
+                generation: [1; NODE_SIZE],
+                free: 0xffffffff as u32,
+                next: DecodeIndex(node_image.next, node_count)?,
+            });
+            node.generation.copy_from_slice(&node_image.generation);
+            let mut values = node_image.values.into_iter();
+            for instance in 0..NODE_SIZE {
+                if (node_image.free & (1 << instance)) == 0 {
+                    node.data[instance].write(values.next().expect("counted above"));
+                    //mark taken as we go so a failure below still drops what was written
+                    node.free &= !(1 << instance);
+                }
             }
+            alloc_count += taken;
+            result.nodes.get_mut().push(Box::into_raw(node));
         }
-        return None;
+
+        if alloc_count as u64 != image.alloc_count {
+            return Err(format!(
+                "{} values but alloc count {}",
+                alloc_count, image.alloc_count
+            ));
+        }
+        CheckGenerations::<L>(&[image.fresh_generation])?;
+        result.alloc_count.set(alloc_count);
+        result.fresh_generation = image.fresh_generation;
+        result
+            .free_list
+            .set(DecodeIndex(image.free_list, node_count)?);
+        result.CheckFreeList()?;
+        return Ok(result);
+    }
+
+    /*
+     * TryAllocate takes the first slot of the head node on trust,
+     * so a restored free list must only chain nodes with room and must end
+     */
+    fn CheckFreeList(&self) -> Result<(), String> {
+        let mut listed = vec![false; self.NodeCount()];
+        let mut index = self.free_list.get();
+        while index != NULL_INDEX {
+            if listed[index] {
+                return Err(format!("free list loops back to node {}", index));
+            }
+            listed[index] = true;
+            //SAFETY: reads bookkeeping in place, see FindNode
+            let (free, next) =
+                unsafe { ((*self.NodePtr(index)).free, (*self.NodePtr(index)).next) };
+            if free == 0 {
+                return Err(format!("full node {} is on the free list", index));
+            }
+            index = next;
+        }
+        return Ok(());
     }
 }
 
-impl<T> Index<usize> for Node<T> {
-    type Output = T;
+impl<T: Serialize, L: HandleLayout> Serialize for SparceBuffer<T, L> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        return self.Image().serialize(serializer);
+    }
+}
 
-    fn index(&self, index: usize) -> &T {
-        unsafe {
-            return &*self.data[index].as_ptr();
+impl<'de, T: DeserializeOwned, L: HandleLayout> Deserialize<'de> for SparceBuffer<T, L> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let image = BufferImage::<T>::deserialize(deserializer)?;
+        return Self::FromImage(image).map_err(serde::de::Error::custom);
+    }
+}
+
+impl<T, L: HandleLayout> Drop for SparceBuffer<T, L> {
+    fn drop(&mut self) {
+        for node in self.nodes.get_mut().drain(..) {
+            //SAFETY: every node came from Box::into_raw in Grow or FromImage, Compact
+            //takes the ones it releases out of the vec
+            let mut node = unsafe { Box::from_raw(node) };
+            for instance in 0..NODE_SIZE {
+                if (node.free & (1 << instance)) == 0 {
+                    unsafe { node.data[instance].assume_init_drop() };
+                }
+            }
+            node.free = 0xffffffff;
         }
     }
 }
 
-impl<T> IndexMut<usize> for Node<T> {
-    fn index_mut(&mut self, index: usize) -> &mut T {
-        unsafe {
-            return &mut *self.data[index].as_mut_ptr();
+impl SlotCursor {
+    fn new() -> Self {
+        return Self {
+            node: 0,
+            instance: 0,
+        };
+    }
+
+    //returns the (node, instance) of the next taken slot
+    fn Next<T, L: HandleLayout>(&mut self, buffer: &SparceBuffer<T, L>) -> Option<(usize, usize)> {
+        while self.node < buffer.NodeCount() {
+            //SAFETY: reads bookkeeping in place, see FindNode
+            let taken = !unsafe { (*buffer.NodePtr(self.node)).free };
+            let remaining = if self.instance < NODE_SIZE {
+                taken & (u32::MAX << self.instance)
+            } else {
+                0
+            };
+            if remaining != 0 {
+                let instance = remaining.trailing_zeros() as usize;
+                self.instance = instance + 1;
+                return Some((self.node, instance));
+            }
+            self.node = self.node + 1;
+            self.instance = 0;
         }
+        return None;
+    }
+}
+
+impl<'a, T, L: HandleLayout> Iterator for SparceBufferIter<'a, T, L> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, instance) = self.cursor.Next(self.buffer)?;
+        //SAFETY: the slot is taken, see TryGet
+        return Some(unsafe { &*SlotPtr(self.buffer.NodePtr(node), instance) });
+    }
+}
+
+impl<'a, T, L: HandleLayout> Iterator for SparceBufferHandleIter<'a, T, L> {
+    type Item = (handle::handle_t<T, L>, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, instance) = self.cursor.Next(self.buffer)?;
+        let h = self.buffer.HandleAt(node, instance);
+        //SAFETY: the slot is taken, see TryGet
+        return Some((h, unsafe { &*SlotPtr(self.buffer.NodePtr(node), instance) }));
     }
 }
 
-impl<T> Index<handle::handle_t<T>> for SparceBuffer<T> {
+impl<'a, T, L: HandleLayout> Iterator for SparceBufferIterMut<'a, T, L> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, instance) = self.cursor.Next(self.buffer)?;
+        //SAFETY: the iterator holds the buffer's &mut borrow and yields every slot once
+        return Some(unsafe { &mut *SlotPtr(self.buffer.NodePtr(node), instance) });
+    }
+}
+
+impl<'a, T, L: HandleLayout> Iterator for SparceBufferDrain<'a, T, L> {
+    type Item = (handle::handle_t<T, L>, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, instance) = self.cursor.Next(self.buffer)?;
+        let h = self.buffer.HandleAt(node, instance);
+        let value = self.buffer.Remove(h)?;
+        return Some((h, value));
+    }
+}
+
+impl<'a, T, L: HandleLayout> Drop for SparceBufferDrain<'a, T, L> {
+    fn drop(&mut self) {
+        while let Some(_) = self.next() {}
+    }
+}
+
+impl<T, L: HandleLayout> Index<handle::handle_t<T, L>> for SparceBuffer<T, L> {
     type Output = T;
 
-    fn index(&self, index: handle::handle_t<T>) -> &T {
-        unsafe {
-            let optional_box = &*self.buffer[index.Node() as usize].get();
-            let node = optional_box.as_deref().unwrap();
-            return &node[index.Instance() as usize];
-        }
+    fn index(&self, index: handle::handle_t<T, L>) -> &T {
+        return self.TryGet(index).expect("null or stale handle");
     }
 }
 
-impl<T> IndexMut<handle::handle_t<T>> for SparceBuffer<T> {
-    fn index_mut(&mut self, index: handle::handle_t<T>) -> &mut T {
-        unsafe {
-            let optional_box = &mut *self.buffer[index.Node() as usize].get();
-            let node: &mut Node<T> = optional_box.as_deref_mut().unwrap();
-            return &mut node[index.Instance() as usize];
-        }
+impl<T, L: HandleLayout> IndexMut<handle::handle_t<T, L>> for SparceBuffer<T, L> {
+    fn index_mut(&mut self, index: handle::handle_t<T, L>) -> &mut T {
+        return self.TryGetMut(index).expect("null or stale handle");
     }
 }
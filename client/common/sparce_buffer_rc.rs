@@ -1,79 +1,352 @@
-use handle::handle_t;
+use handle::{handle_t, HandleLayout};
 use std::cell::{Cell, UnsafeCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::rc::{Rc, Weak};
 
+/*
+ * value is None while the slot is free, so releasing a slot drops what it held
+ * generation is bumped on release so handles to the old value go stale
+ */
 #[repr(C)]
+struct RcPtr<T> {
+    rc: i32,
+    generation: u32,
+    borrow: BorrowFlag,
+    value: Option<T>,
+}
+
+impl<T> Default for RcPtr<T> {
+    fn default() -> Self {
+        return Self {
+            rc: 0,
+            generation: 1,
+            borrow: BorrowFlag::default(),
+            value: None,
+        };
+    }
+}
+
+/*
+ * RefCell style borrow state of a slot
+ * count > 0 is the number of shared guards, -1 is a single exclusive guard
+ *
+ * With debug_assertions we also remember where the last guard was taken
+ * so a conflicting borrow can point at the code holding the slot
+ */
 #[derive(Default)]
+struct BorrowFlag {
+    count: Cell<isize>,
+    #[cfg(debug_assertions)]
+    borrowed_at: Cell<Option<&'static Location<'static>>>,
+}
 
-struct RcPtr<T: Default> {
-    rc: i32,
-    value: T,
+impl BorrowFlag {
+    fn Record(&self, _location: &'static Location<'static>) {
+        #[cfg(debug_assertions)]
+        self.borrowed_at.set(Some(_location));
+    }
+
+    fn BorrowedAt(&self) -> Option<&'static Location<'static>> {
+        #[cfg(debug_assertions)]
+        return self.borrowed_at.get();
+        #[cfg(not(debug_assertions))]
+        return None;
+    }
+
+    fn Release(&self) {
+        #[cfg(debug_assertions)]
+        if self.count.get() == 0 {
+            self.borrowed_at.set(None);
+        }
+    }
+}
+
+/*
+ * Returned by TryGet/TryGetMut when a guard can't be handed out
+ * borrowed_at is only filled in with debug_assertions
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BorrowError {
+    //the handle is null or its value was released
+    InvalidHandle,
+    //asked for an exclusive guard while shared guards are alive
+    AlreadyBorrowed {
+        borrowed_at: Option<&'static Location<'static>>,
+    },
+    //asked for any guard while an exclusive guard is alive
+    AlreadyMutablyBorrowed {
+        borrowed_at: Option<&'static Location<'static>>,
+    },
+}
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (message, borrowed_at) = match self {
+            BorrowError::InvalidHandle => return write!(f, "null or stale handle"),
+            BorrowError::AlreadyBorrowed { borrowed_at } => ("already borrowed", borrowed_at),
+            BorrowError::AlreadyMutablyBorrowed { borrowed_at } => {
+                ("already mutably borrowed", borrowed_at)
+            }
+        };
+        return match borrowed_at {
+            Some(location) => write!(f, "{} at {}", message, location),
+            None => write!(f, "{}", message),
+        };
+    }
 }
 
-struct Node<T: Default> {
-    buffer: [RcPtr<T>; 64],
+impl std::error::Error for BorrowError {}
+
+const NODE_SIZE: usize = 64;
+
+//buffer is boxed rather than an array so nodes of large values are built on the heap,
+//an array would be assembled on the stack first
+//it is kept as a raw pointer so reaching one slot never borrows the others, see Slot
+struct Node<T> {
+    buffer: *mut [RcPtr<T>],
     bitmask: u64, //1 = free, 0 = taken
     next: i32,
 }
 
-impl<T: Default> Default for Node<T> {
+impl<T> Node<T> {
+    fn WithGeneration(generation: u32) -> Self {
+        let result = Self::default();
+        //SAFETY: the node was just made, nothing else points into it
+        for rc_ptr in unsafe { &mut *result.buffer }.iter_mut() {
+            rc_ptr.generation = generation;
+        }
+        return result;
+    }
+
+    /*
+     * Points at one slot without borrowing the rest of the node
+     * Guards hold references into slots, so a &mut over the whole buffer would invalidate them
+     */
+    fn Slot(&self, instance: usize) -> *mut RcPtr<T> {
+        assert!(instance < NODE_SIZE);
+        //SAFETY: instance is in bounds of the NODE_SIZE long buffer
+        return unsafe { (self.buffer as *mut RcPtr<T>).add(instance) };
+    }
+}
+
+impl<T> Default for Node<T> {
     fn default() -> Self {
+        let buffer: Box<[RcPtr<T>]> = (0..NODE_SIZE).map(|_| RcPtr::default()).collect();
         return Self {
-            buffer: std::array::from_fn(|_| RcPtr::default()),
+            buffer: Box::into_raw(buffer),
             bitmask: 0xFFFFFFFFFFFFFFFF,
             next: -1,
         };
     }
 }
 
-pub struct SparceBufferRc<T: Default> {
+impl<T> Drop for Node<T> {
+    fn drop(&mut self) {
+        //SAFETY: buffer came from Box::into_raw in Default and is only released here
+        drop(unsafe { Box::from_raw(self.buffer) });
+    }
+}
+
+pub struct SparceBufferRc<T, L: HandleLayout = handle::Handle32> {
     nodes: UnsafeCell<Vec<Box<Node<T>>>>,
     free_list: Cell<i32>,
+    //generation new nodes start at, see handle::CompactSlots
+    fresh_generation: u32,
+    _layout: PhantomData<L>,
+}
+
+/*
+ * Returned by TryAllocate once every node the handle layout can address is full
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError {
+    pub max_nodes: usize,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "SparceBufferRc is out of capacity ({} nodes of {} slots)",
+            self.max_nodes, NODE_SIZE
+        );
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/*
+ * Owning reference to a slot, Clone bumps the ref count and Drop releases it
+ * Holds the buffer through an Rc, so it can be stored anywhere and keeps the buffer alive
+ */
+pub struct RcHandle<T, L: HandleLayout = handle::Handle32> {
+    buffer: Rc<SparceBufferRc<T, L>>,
+    handle: handle_t<T, L>,
+}
+
+/*
+ * Non owning reference to a slot, can be upgraded only while the value is alive
+ * Keeps neither the value nor the buffer alive
+ */
+pub struct WeakHandle<T, L: HandleLayout = handle::Handle32> {
+    buffer: Weak<SparceBufferRc<T, L>>,
+    handle: handle_t<T, L>,
+}
+
+/*
+ * Shared guard, any number can be alive for a slot as long as there is no BufferRefMut
+ */
+pub struct BufferRef<'a, T> {
+    value: &'a T,
+    borrow: &'a BorrowFlag,
 }
 
-pub struct BufferGuard<'a, T> {
+/*
+ * Exclusive guard, at most one can be alive for a slot
+ */
+pub struct BufferRefMut<'a, T> {
     value: &'a mut T,
+    borrow: &'a BorrowFlag,
 }
 
-impl<'a, T> Deref for BufferGuard<'a, T> {
+impl<'a, T> Deref for BufferRef<'a, T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
         self.value
     }
 }
 
-impl<'a, T> DerefMut for BufferGuard<'a, T> {
+impl<'a, T> Drop for BufferRef<'a, T> {
+    fn drop(&mut self) {
+        self.borrow.count.set(self.borrow.count.get() - 1);
+        self.borrow.Release();
+    }
+}
+
+impl<'a, T> Deref for BufferRefMut<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<'a, T> DerefMut for BufferRefMut<'a, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.value
     }
 }
 
-impl<T: Default> SparceBufferRc<T> {
+impl<'a, T> Drop for BufferRefMut<'a, T> {
+    fn drop(&mut self) {
+        self.borrow.count.set(0);
+        self.borrow.Release();
+    }
+}
+
+unsafe impl<T: Send, L: HandleLayout> Send for SparceBufferRc<T, L> {}
+
+impl<T, L: HandleLayout> SparceBufferRc<T, L> {
     pub fn new() -> Self {
         Self {
             nodes: UnsafeCell::new(Vec::new()),
             free_list: Cell::new(-1),
+            fresh_generation: 1,
+            _layout: PhantomData,
         }
     }
 
-    pub fn Get(&self, h: handle_t<T>) -> BufferGuard<'_, T> {
-        let node_idx = h.Node() as usize;
-        let buf_idx = h.Value() as usize;
+    /*
+     * Panics if h is stale or the slot is mutably borrowed, see TryGet
+     */
+    #[track_caller]
+    pub fn Get(&self, h: handle_t<T, L>) -> BufferRef<'_, T> {
+        return match self.TryGet(h) {
+            Ok(guard) => guard,
+            Err(err) => panic!("SparceBufferRc::Get: {}", err),
+        };
+    }
+
+    /*
+     * Panics if h is stale or the slot is borrowed at all, see TryGetMut
+     */
+    #[track_caller]
+    pub fn GetMut(&self, h: handle_t<T, L>) -> BufferRefMut<'_, T> {
+        return match self.TryGetMut(h) {
+            Ok(guard) => guard,
+            Err(err) => panic!("SparceBufferRc::GetMut: {}", err),
+        };
+    }
 
-        unsafe {
-            let vec_ptr = self.nodes.get();
-            let node = &mut *(*vec_ptr)[node_idx];
-            let value = &mut node.buffer[buf_idx].value;
-            BufferGuard { value }
+    #[track_caller]
+    pub fn TryGet(&self, h: handle_t<T, L>) -> Result<BufferRef<'_, T>, BorrowError> {
+        let rc_ptr = self.FindSlot(h).ok_or(BorrowError::InvalidHandle)?;
+        //SAFETY: the flag is only ever shared, it is a Cell
+        let borrow = unsafe { &(*rc_ptr).borrow };
+        if borrow.count.get() < 0 {
+            return Err(BorrowError::AlreadyMutablyBorrowed {
+                borrowed_at: borrow.BorrowedAt(),
+            });
         }
+        borrow.count.set(borrow.count.get() + 1);
+        borrow.Record(Location::caller());
+
+        //SAFETY: the flag shows no exclusive guard, and the value is only taken out by
+        //Release, which refuses while any guard is alive
+        let value = unsafe { (*rc_ptr).value.as_ref() }.expect("live slot without a value");
+        return Ok(BufferRef { value, borrow });
     }
 
-    pub fn Allocate(&self, default: T) -> handle_t<T> {
+    #[track_caller]
+    pub fn TryGetMut(&self, h: handle_t<T, L>) -> Result<BufferRefMut<'_, T>, BorrowError> {
+        let rc_ptr = self.FindSlot(h).ok_or(BorrowError::InvalidHandle)?;
+        //SAFETY: the flag is only ever shared, it is a Cell
+        let borrow = unsafe { &(*rc_ptr).borrow };
+        if borrow.count.get() < 0 {
+            return Err(BorrowError::AlreadyMutablyBorrowed {
+                borrowed_at: borrow.BorrowedAt(),
+            });
+        }
+        if borrow.count.get() > 0 {
+            return Err(BorrowError::AlreadyBorrowed {
+                borrowed_at: borrow.BorrowedAt(),
+            });
+        }
+        borrow.count.set(-1);
+        borrow.Record(Location::caller());
+
+        //SAFETY: the flag shows no other guard on the slot, and it now marks this one exclusive
+        let value = unsafe { (*rc_ptr).value.as_mut() }.expect("live slot without a value");
+        return Ok(BufferRefMut { value, borrow });
+    }
+
+    /*
+     * Panics once the buffer is out of capacity, see TryAllocate
+     */
+    pub fn Allocate(&self, value: T) -> handle_t<T, L> {
+        return self
+            .TryAllocate(value)
+            .expect("SparceBufferRc allocation failed");
+    }
+
+    /*
+     * Fails instead of growing past the nodes a handle can address,
+     * a node index past that would spill into the generation bits
+     */
+    pub fn TryAllocate(&self, value: T) -> Result<handle_t<T, L>, AllocError> {
         unsafe {
             let nodes = &mut *self.nodes.get();
 
             if self.free_list.get() == -1 {
-                let new_node = Box::new(Node::default());
+                if nodes.len() >= MaxNodes::<L>() {
+                    return Err(AllocError {
+                        max_nodes: MaxNodes::<L>(),
+                    });
+                }
+                let new_node = Box::new(Node::WithGeneration(self.fresh_generation));
                 nodes.push(new_node);
                 self.free_list.set((nodes.len() - 1) as i32);
             }
@@ -82,9 +355,11 @@ impl<T: Default> SparceBufferRc<T> {
             let node = &mut *nodes[node_idx];
 
             let buffer_index = node.bitmask.trailing_zeros();
-            let entry = &mut node.buffer[buffer_index as usize];
-            entry.rc = 1;
-            entry.value = default;
+            //the slot is free, so no guard points at it
+            let entry = node.Slot(buffer_index as usize);
+            (*entry).rc = 1;
+            (*entry).value = Some(value);
+            let generation = (*entry).generation;
 
             node.bitmask &= !(1 << buffer_index);
             if node.bitmask == 0 {
@@ -93,57 +368,326 @@ impl<T: Default> SparceBufferRc<T> {
                 node.next = -1;
             }
 
-            handle_t::from(0, node_idx as u8, buffer_index as u8)
+            return Ok(handle_t::from(generation, node_idx, buffer_index as usize));
         }
     }
 
-    pub fn BumpRef(&self, h: handle_t<T>) {
-        unsafe {
-            let nodes = &mut *self.nodes.get();
-            if let Some(node) = nodes.get_mut(h.Node() as usize) {
-                let rc_ptr = &mut node.buffer[h.Value() as usize];
-                rc_ptr.rc += 1;
-            }
+    /*
+     * Same as Allocate but the initial reference is owned by the returned RcHandle
+     * Panics once the buffer is out of capacity, like Allocate
+     */
+    pub fn AllocateRc(self: &Rc<Self>, value: T) -> RcHandle<T, L> {
+        return RcHandle {
+            buffer: self.clone(),
+            handle: self.Allocate(value),
+        };
+    }
+
+    /*
+     * Takes a new reference to h, None if h is null or stale
+     */
+    pub fn Acquire(self: &Rc<Self>, h: handle_t<T, L>) -> Option<RcHandle<T, L>> {
+        if !self.IsAlive(h) {
+            return None;
         }
+        self.BumpRef(h);
+        return Some(RcHandle {
+            buffer: self.clone(),
+            handle: h,
+        });
     }
 
-    pub fn Free(&self, h: handle_t<T>) {
-        let mut should_release = false;
+    /*
+     * Returns true while h points at a value that has not been released
+     */
+    pub fn IsAlive(&self, h: handle_t<T, L>) -> bool {
+        return self.FindSlot(h).is_some();
+    }
 
-        unsafe {
-            let nodes = &mut *self.nodes.get();
+    /*
+     * Number of outstanding references, 0 for null or stale handles
+     */
+    pub fn RefCount(&self, h: handle_t<T, L>) -> i32 {
+        return match self.FindSlot(h) {
+            //SAFETY: rc is never borrowed, only read and written in place
+            Some(rc_ptr) => unsafe { (*rc_ptr).rc },
+            None => 0,
+        };
+    }
 
-            if let Some(node) = nodes.get_mut(h.Node() as usize) {
-                let rc_ptr = &mut node.buffer[h.Value() as usize];
+    pub fn BumpRef(&self, h: handle_t<T, L>) {
+        if let Some(rc_ptr) = self.FindSlot(h) {
+            //SAFETY: rc is never borrowed, only read and written in place
+            unsafe { (*rc_ptr).rc += 1 };
+        }
+    }
 
-                rc_ptr.rc -= 1;
+    /*
+     * Drops one reference, the value is released with the last one
+     * Null and stale handles are ignored
+     */
+    pub fn Free(&self, h: handle_t<T, L>) {
+        drop(self.Unref(h));
+    }
+
+    /*
+     * Same as Free, but the last reference hands the value back instead of dropping it
+     * Lets owners of nested handles release the children of what they just freed
+     */
+    pub fn Unref(&self, h: handle_t<T, L>) -> Option<T> {
+        let mut should_release = false;
 
-                if rc_ptr.rc == 0 {
+        if let Some(rc_ptr) = self.FindSlot(h) {
+            //SAFETY: rc is never borrowed, only read and written in place
+            unsafe {
+                (*rc_ptr).rc -= 1;
+
+                if (*rc_ptr).rc == 0 {
                     should_release = true;
                 }
             }
         }
 
         if should_release {
-            self.Release(h);
+            return self.Release(h);
         }
+        return None;
     }
 
-    fn Release(&self, h: handle_t<T>) {
+    /*
+     * Reports how full each node is, see BufferStats::Fragmentation
+     */
+    pub fn Stats(&self) -> handle::BufferStats {
+        //SAFETY: no borrow of the vec outlives the method that takes it
+        let nodes = unsafe { &*self.nodes.get() };
+        return handle::BufferStats::FromOccupancy(
+            NODE_SIZE,
+            nodes
+                .iter()
+                .map(|node| (!node.bitmask).count_ones() as usize)
+                .collect(),
+        );
+    }
+
+    /*
+     * Packs the values into as few nodes as possible, see handle::CompactSlots
+     * Reference counts move with the values, the returned table maps old handles to new
+     * Taking &mut self guarantees no guard or RcHandle is alive across the move
+     */
+    pub fn Compact(&mut self) -> handle::HandleRemap<T, L> {
+        let live = self.Stats().live;
+        return handle::CompactSlots(&mut Compaction(self), live);
+    }
+
+    /*
+     * Returns the slot h points at if it is taken and the generation matches
+     */
+    fn FindSlot(&self, h: handle_t<T, L>) -> Option<*mut RcPtr<T>> {
+        if h.IsNull() {
+            return None;
+        }
+        //SAFETY: no borrow of the vec outlives the method that takes it
+        let nodes = unsafe { &*self.nodes.get() };
+        let node = nodes.get(h.Node())?;
+        if (node.bitmask & (1 << h.Instance())) != 0 {
+            return None;
+        }
+        let rc_ptr = node.Slot(h.Instance());
+        //SAFETY: generation is never borrowed, only read and written in place
+        if unsafe { (*rc_ptr).generation } != h.Generation() {
+            return None;
+        }
+        return Some(rc_ptr);
+    }
+
+    fn Release(&self, h: handle_t<T, L>) -> Option<T> {
+        let mut released = None;
+
         unsafe {
             let nodes = &mut *self.nodes.get();
-            let node_idx = h.Node() as usize;
-            let slot_idx = h.Value() as usize;
+            let node_idx = h.Node();
+            let slot_idx = h.Instance();
 
             if let Some(node) = nodes.get_mut(node_idx) {
-                node.bitmask |= 1 << slot_idx;
-                let old_free_head = self.free_list.get();
-
-                if old_free_head != node_idx as i32 {
-                    node.next = old_free_head;
+                let rc_ptr = node.Slot(slot_idx);
+                //a guard still points at the value, dropping it now would leave it dangling
+                let borrow = &(*rc_ptr).borrow;
+                if borrow.count.get() != 0 {
+                    panic!(
+                        "SparceBufferRc: value released while borrowed at {:?}",
+                        borrow.BorrowedAt()
+                    );
+                }
+                //a full node was dropped from the free list, put it back
+                if node.bitmask == 0 {
+                    node.next = self.free_list.get();
                     self.free_list.set(node_idx as i32);
                 }
+                node.bitmask |= 1 << slot_idx;
+                (*rc_ptr).generation = L::NextGeneration((*rc_ptr).generation);
+                released = (*rc_ptr).value.take();
             }
         }
+
+        //handed out once the slot is back on the free list, so a Drop impl
+        //that touches this buffer sees consistent state
+        return released;
+    }
+}
+
+//the layout's node limit, and the i32 free list links can't go past i32::MAX either
+fn MaxNodes<L: HandleLayout>() -> usize {
+    return L::MAX_NODES.min(i32::MAX as u64) as usize;
+}
+
+//what handle::CompactSlots works through, kept private so only Compact moves values
+struct Compaction<'a, T, L: HandleLayout>(&'a mut SparceBufferRc<T, L>);
+
+impl<'a, T, L: HandleLayout> Compaction<'a, T, L> {
+    fn Nodes(&self) -> &Vec<Box<Node<T>>> {
+        //SAFETY: the view holds the buffer's &mut borrow
+        return unsafe { &*self.0.nodes.get() };
+    }
+}
+
+impl<'a, T, L: HandleLayout> handle::NodeSlots<T, L> for Compaction<'a, T, L> {
+    const NODE_SIZE: usize = NODE_SIZE;
+
+    fn NodeCount(&self) -> usize {
+        return self.Nodes().len();
+    }
+
+    fn IsTaken(&self, slot: usize) -> bool {
+        return (self.Nodes()[slot / NODE_SIZE].bitmask & (1 << (slot % NODE_SIZE))) == 0;
+    }
+
+    fn HandleAt(&self, slot: usize) -> handle_t<T, L> {
+        let rc_ptr = self.Nodes()[slot / NODE_SIZE].Slot(slot % NODE_SIZE);
+        //SAFETY: generation is never borrowed, only read and written in place
+        let generation = unsafe { (*rc_ptr).generation };
+        return handle_t::from(generation, slot / NODE_SIZE, slot % NODE_SIZE);
+    }
+
+    fn MoveSlot(&mut self, from: usize, to: usize) {
+        let nodes = self.0.nodes.get_mut();
+        //SAFETY: &mut self means no guard points into any slot
+        let source = &mut nodes[from / NODE_SIZE];
+        source.bitmask |= 1 << (from % NODE_SIZE);
+        let from = unsafe { &mut *source.Slot(from % NODE_SIZE) };
+        from.generation = L::NextGeneration(from.generation);
+        let rc = std::mem::replace(&mut from.rc, 0);
+        let value = from.value.take();
+
+        let target = &mut nodes[to / NODE_SIZE];
+        target.bitmask &= !(1 << (to % NODE_SIZE));
+        let to = unsafe { &mut *target.Slot(to % NODE_SIZE) };
+        to.rc = rc;
+        to.value = value;
+    }
+
+    fn PopNode(&mut self) -> u32 {
+        let node = self.0.nodes.get_mut().pop().expect("no node to pop");
+        //SAFETY: &mut self means no guard points into any slot
+        let slots = unsafe { &*node.buffer };
+        return slots
+            .iter()
+            .map(|rc_ptr| rc_ptr.generation)
+            .max()
+            .expect("");
+    }
+
+    fn RaiseFreshGeneration(&mut self, generation: u32) {
+        self.0.fresh_generation = self.0.fresh_generation.max(generation);
+    }
+
+    fn HasRoom(&self, node: usize) -> bool {
+        return self.Nodes()[node].bitmask != 0;
+    }
+
+    fn SetNextFree(&mut self, node: usize, next: Option<usize>) {
+        self.0.nodes.get_mut()[node].next = next.map_or(-1, |next| next as i32);
+    }
+
+    fn SetFreeHead(&mut self, head: Option<usize>) {
+        self.0.free_list.set(head.map_or(-1, |head| head as i32));
+    }
+}
+
+impl<T, L: HandleLayout> RcHandle<T, L> {
+    /*
+     * The raw handle, it does not keep the value alive on its own
+     */
+    pub fn Handle(&self) -> handle_t<T, L> {
+        return self.handle;
+    }
+
+    pub fn Buffer(&self) -> &Rc<SparceBufferRc<T, L>> {
+        return &self.buffer;
+    }
+
+    #[track_caller]
+    pub fn Get(&self) -> BufferRef<'_, T> {
+        return self.buffer.Get(self.handle);
+    }
+
+    #[track_caller]
+    pub fn GetMut(&self) -> BufferRefMut<'_, T> {
+        return self.buffer.GetMut(self.handle);
+    }
+
+    pub fn Downgrade(&self) -> WeakHandle<T, L> {
+        return WeakHandle {
+            buffer: Rc::downgrade(&self.buffer),
+            handle: self.handle,
+        };
+    }
+
+    /*
+     * Gives up ownership without releasing, the caller is now responsible for a Free
+     */
+    pub fn IntoRaw(self) -> handle_t<T, L> {
+        let this = ManuallyDrop::new(self);
+        //SAFETY: this is never touched again, so the Rc is moved out exactly once
+        drop(unsafe { std::ptr::read(&this.buffer) });
+        return this.handle;
+    }
+}
+
+impl<T, L: HandleLayout> Clone for RcHandle<T, L> {
+    fn clone(&self) -> Self {
+        self.buffer.BumpRef(self.handle);
+        return Self {
+            buffer: self.buffer.clone(),
+            handle: self.handle,
+        };
+    }
+}
+
+impl<T, L: HandleLayout> Drop for RcHandle<T, L> {
+    fn drop(&mut self) {
+        self.buffer.Free(self.handle);
+    }
+}
+
+impl<T, L: HandleLayout> WeakHandle<T, L> {
+    pub fn Handle(&self) -> handle_t<T, L> {
+        return self.handle;
+    }
+
+    /*
+     * None once the last RcHandle (or manual reference) has been released,
+     * or the buffer itself is gone
+     */
+    pub fn Upgrade(&self) -> Option<RcHandle<T, L>> {
+        return self.buffer.upgrade()?.Acquire(self.handle);
+    }
+}
+
+impl<T, L: HandleLayout> Clone for WeakHandle<T, L> {
+    fn clone(&self) -> Self {
+        return Self {
+            buffer: self.buffer.clone(),
+            handle: self.handle,
+        };
     }
 }
@@ -0,0 +1,247 @@
+extern crate handle;
+extern crate sparce_buffer_rc;
+extern crate test_support;
+
+use sparce_buffer_rc::{AllocError, BorrowError, RcHandle, SparceBufferRc};
+use std::cell::Cell;
+use std::rc::Rc;
+use test_support::{Counter, DropCounter, Tiny};
+
+#[test]
+fn last_free_drops_value() {
+    let drops = Rc::new(Cell::new(0));
+    let buffer = SparceBufferRc::<DropCounter>::new();
+    let h = buffer.Allocate(Counter(&drops));
+    buffer.BumpRef(h);
+
+    buffer.Free(h);
+    assert_eq!(drops.get(), 0);
+
+    buffer.Free(h);
+    assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn drop_buffer_drops_live_values_once() {
+    let drops = Rc::new(Cell::new(0));
+    {
+        let buffer = SparceBufferRc::<DropCounter>::new();
+        let handles: Vec<_> = (0..200).map(|_| buffer.Allocate(Counter(&drops))).collect();
+        for h in handles.iter().step_by(2) {
+            buffer.Free(*h);
+        }
+        assert_eq!(drops.get(), 100);
+    }
+    assert_eq!(drops.get(), 200);
+}
+
+#[test]
+fn reused_slot_drops_each_value_once() {
+    let drops = Rc::new(Cell::new(0));
+    {
+        let buffer = SparceBufferRc::<DropCounter>::new();
+        for _ in 0..10 {
+            let h = buffer.Allocate(Counter(&drops));
+            buffer.Free(h);
+        }
+        assert_eq!(drops.get(), 10);
+        buffer.Allocate(Counter(&drops));
+    }
+    assert_eq!(drops.get(), 11);
+}
+
+#[test]
+fn holds_heap_values() {
+    let buffer = SparceBufferRc::<Vec<i32>>::new();
+    let h1 = buffer.Allocate(vec![1, 2, 3]);
+    let h2 = buffer.Allocate(vec![4]);
+    buffer.Free(h1);
+    let h3 = buffer.Allocate(vec![5, 6]);
+
+    assert_eq!(*buffer.Get(h2), vec![4]);
+    assert_eq!(*buffer.Get(h3), vec![5, 6]);
+}
+
+#[test]
+fn rc_handle_releases_on_last_drop() {
+    let drops = Rc::new(Cell::new(0));
+    let buffer = Rc::new(SparceBufferRc::<DropCounter>::new());
+    let a = buffer.AllocateRc(Counter(&drops));
+    let b = a.clone();
+    assert_eq!(buffer.RefCount(a.Handle()), 2);
+
+    drop(a);
+    assert_eq!(drops.get(), 0);
+    assert!(buffer.IsAlive(b.Handle()));
+
+    let h = b.Handle();
+    drop(b);
+    assert_eq!(drops.get(), 1);
+    assert!(!buffer.IsAlive(h));
+}
+
+#[test]
+fn weak_handle_upgrades_only_while_alive() {
+    let buffer = Rc::new(SparceBufferRc::<i32>::new());
+    let strong = buffer.AllocateRc(7);
+    let weak = strong.Downgrade();
+
+    {
+        let upgraded = weak.Upgrade().expect("value is still alive");
+        assert_eq!(*upgraded.Get(), 7);
+        assert_eq!(buffer.RefCount(strong.Handle()), 2);
+    }
+    assert_eq!(buffer.RefCount(strong.Handle()), 1);
+
+    drop(strong);
+    assert!(weak.Upgrade().is_none());
+
+    //the slot is reused but the weak handle must not see the new value
+    let other = buffer.AllocateRc(9);
+    assert_eq!(other.Handle().Node(), weak.Handle().Node());
+    assert_eq!(other.Handle().Instance(), weak.Handle().Instance());
+    assert!(weak.Upgrade().is_none());
+
+    let live = other.Downgrade();
+    drop(other);
+    drop(buffer);
+    assert!(live.Upgrade().is_none());
+}
+
+/*
+ * Owns its handles outright, no borrow of the buffer to thread through
+ */
+struct Inventory {
+    items: Vec<RcHandle<String>>,
+}
+
+#[test]
+fn rc_handles_can_be_stored() {
+    let buffer = Rc::new(SparceBufferRc::<String>::new());
+    let sword = buffer.AllocateRc(String::from("sword"));
+    let mut inventory = Inventory {
+        items: vec![sword.clone(), buffer.AllocateRc(String::from("shield"))],
+    };
+    let h = sword.Handle();
+    drop(sword);
+    //the handles keep the buffer alive on their own
+    drop(buffer);
+    assert_eq!(*inventory.items[0].Get(), "sword");
+    assert_eq!(inventory.items[0].Buffer().RefCount(h), 1);
+
+    let buffer = inventory.items[0].Buffer().clone();
+    inventory.items.remove(0);
+    assert!(!buffer.IsAlive(h));
+
+    //IntoRaw hands the reference over but lets go of the buffer
+    let raw = inventory.items.pop().unwrap().IntoRaw();
+    assert_eq!(Rc::strong_count(&buffer), 1);
+    assert_eq!(buffer.RefCount(raw), 1);
+    buffer.Free(raw);
+    assert!(!buffer.IsAlive(raw));
+}
+
+#[test]
+fn stale_handles_are_ignored() {
+    let buffer = SparceBufferRc::<i32>::new();
+    let h = buffer.Allocate(1);
+    buffer.Free(h);
+    let h2 = buffer.Allocate(2);
+
+    buffer.BumpRef(h);
+    buffer.Free(h);
+    assert_eq!(buffer.RefCount(h2), 1);
+    assert_eq!(*buffer.Get(h2), 2);
+}
+
+#[test]
+fn shared_guards_coexist() {
+    let buffer = SparceBufferRc::<i32>::new();
+    let h = buffer.Allocate(3);
+    let a = buffer.Get(h);
+    let b = buffer.Get(h);
+    assert_eq!(*a + *b, 6);
+    assert!(buffer.TryGetMut(h).is_err());
+}
+
+#[test]
+fn exclusive_guard_blocks_other_borrows() {
+    let buffer = SparceBufferRc::<i32>::new();
+    let h = buffer.Allocate(3);
+    {
+        let mut guard = buffer.GetMut(h);
+        *guard = 4;
+
+        match buffer.TryGet(h) {
+            Err(BorrowError::AlreadyMutablyBorrowed { borrowed_at }) => {
+                if cfg!(debug_assertions) {
+                    assert_eq!(borrowed_at.expect("location").file(), file!());
+                }
+            }
+            _ => panic!("expected a borrow conflict"),
+        }
+        assert!(buffer.TryGetMut(h).is_err());
+    }
+    assert_eq!(*buffer.Get(h), 4);
+    assert!(buffer.TryGetMut(h).is_ok());
+}
+
+#[test]
+#[should_panic(expected = "already mutably borrowed")]
+fn conflicting_get_panics() {
+    let buffer = SparceBufferRc::<i32>::new();
+    let h = buffer.Allocate(3);
+    let _guard = buffer.GetMut(h);
+    let _other = buffer.Get(h);
+}
+
+#[test]
+fn stale_handle_is_an_error() {
+    let buffer = SparceBufferRc::<i32>::new();
+    let h = buffer.Allocate(3);
+    buffer.Free(h);
+    assert_eq!(buffer.TryGet(h).err(), Some(BorrowError::InvalidHandle));
+}
+
+#[test]
+fn compact_keeps_ref_counts() {
+    let mut buffer = SparceBufferRc::<usize>::new();
+    let handles: Vec<_> = (0..300).map(|i| buffer.Allocate(i)).collect();
+    for (i, h) in handles.iter().enumerate() {
+        if i % 4 != 0 {
+            buffer.Free(*h);
+        } else {
+            buffer.BumpRef(*h);
+        }
+    }
+    assert_eq!(buffer.Stats().NodeCount(), 5);
+
+    let remap = buffer.Compact();
+    assert_eq!(buffer.Stats().NodeCount(), 2);
+    for (i, h) in handles.iter().enumerate().step_by(4) {
+        let moved = *remap.get(h).unwrap_or(h);
+        assert_eq!(*buffer.Get(moved), i);
+        assert_eq!(buffer.RefCount(moved), 2);
+    }
+    for (old, _) in remap.iter() {
+        assert!(!buffer.IsAlive(*old));
+    }
+}
+
+#[test]
+fn allocation_fails_once_the_layout_is_full() {
+    //4 nodes of 64
+    let buffer = Rc::new(SparceBufferRc::<u32, Tiny>::new());
+    let handles: Vec<_> = (0..256).map(|i| buffer.TryAllocate(i).unwrap()).collect();
+    assert!(handles.iter().all(|h| h.Node() < 4));
+    assert_eq!(buffer.TryAllocate(256), Err(AllocError { max_nodes: 4 }));
+
+    //releasing the last reference makes room again
+    buffer.Free(handles[100]);
+    let h = buffer.TryAllocate(300).unwrap();
+    assert_eq!(*buffer.Get(h), 300);
+    assert!(buffer.TryAllocate(301).is_err());
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| buffer.AllocateRc(302)));
+    assert!(result.is_err());
+}
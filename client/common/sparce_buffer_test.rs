@@ -0,0 +1,353 @@
+extern crate handle;
+extern crate sparce_buffer;
+extern crate test_support;
+
+use handle::HandleLayout;
+use sparce_buffer::{AllocError, BufferImage, SnapshotError, SparceBuffer};
+use std::cell::Cell;
+use std::rc::Rc;
+use test_support::{Counter, DropCounter, Tiny};
+
+#[test]
+fn free_drops_value() {
+    let drops = Rc::new(Cell::new(0));
+    let mut buffer = SparceBuffer::<DropCounter>::new();
+    let h1 = buffer.Allocate(Counter(&drops));
+    let h2 = buffer.Allocate(Counter(&drops));
+
+    buffer.Free(h1);
+    assert_eq!(drops.get(), 1);
+
+    //freeing a stale handle must not drop anything again
+    buffer.Free(h1);
+    assert_eq!(drops.get(), 1);
+
+    buffer.Free(h2);
+    assert_eq!(drops.get(), 2);
+}
+
+#[test]
+fn drop_buffer_drops_live_values_once() {
+    let drops = Rc::new(Cell::new(0));
+    {
+        let mut buffer = SparceBuffer::<DropCounter>::new();
+        let handles: Vec<_> = (0..100).map(|_| buffer.Allocate(Counter(&drops))).collect();
+        for h in handles.iter().step_by(2) {
+            buffer.Free(*h);
+        }
+        assert_eq!(drops.get(), 50);
+    }
+    assert_eq!(drops.get(), 100);
+}
+
+#[test]
+fn reused_slot_drops_each_value_once() {
+    let drops = Rc::new(Cell::new(0));
+    {
+        let mut buffer = SparceBuffer::<DropCounter>::new();
+        for _ in 0..10 {
+            let h = buffer.Allocate(Counter(&drops));
+            buffer.Free(h);
+        }
+        assert_eq!(drops.get(), 10);
+        buffer.Allocate(Counter(&drops));
+    }
+    assert_eq!(drops.get(), 11);
+}
+
+#[test]
+fn holds_heap_values() {
+    let mut buffer = SparceBuffer::<String>::new();
+    let h1 = buffer.Allocate(String::from("goblin"));
+    let h2 = buffer.Allocate(String::from("orc"));
+    buffer.Free(h1);
+    let h3 = buffer.Allocate(String::from("troll"));
+
+    assert_eq!(buffer.TryGet(h1), None);
+    assert_eq!(buffer[h2], "orc");
+    assert_eq!(buffer[h3], "troll");
+}
+
+#[test]
+fn snapshot_restores_handles() {
+    let mut buffer = SparceBuffer::<String>::new();
+    let handles: Vec<_> = (0..100)
+        .map(|i| buffer.Allocate(format!("creature {}", i)))
+        .collect();
+    for h in handles.iter().step_by(3) {
+        buffer.Free(*h);
+    }
+
+    let restored = SparceBuffer::<String>::Restore(&buffer.Snapshot().unwrap()).unwrap();
+    assert_eq!(restored.Size(), buffer.Size());
+    for h in handles.iter() {
+        assert_eq!(restored.TryGet(*h), buffer.TryGet(*h));
+    }
+
+    //the free list carries over, so both buffers hand out the same handle next
+    assert_eq!(
+        restored.Allocate(String::from("new")),
+        buffer.Allocate(String::from("new"))
+    );
+}
+
+#[test]
+fn restore_rejects_corrupt_bytes() {
+    let buffer = SparceBuffer::<u32>::new();
+    buffer.Allocate(1);
+    let bytes = buffer.Snapshot().unwrap();
+
+    assert!(SparceBuffer::<u32>::Restore(&bytes[..bytes.len() - 1]).is_err());
+    assert!(SparceBuffer::<u32, handle::Handle64>::Restore(&bytes).is_err());
+}
+
+#[test]
+fn iterators_skip_free_slots() {
+    let mut buffer = SparceBuffer::<u32>::new();
+    let handles: Vec<_> = (0..70).map(|i| buffer.Allocate(i)).collect();
+    for h in handles.iter().step_by(3) {
+        buffer.Free(*h);
+    }
+
+    let mut seen: Vec<_> = buffer.IterWithHandles().collect();
+    seen.sort_by_key(|(_, value)| **value);
+    let expected: Vec<_> = (0..70).filter(|i| i % 3 != 0).collect();
+    assert_eq!(
+        seen.iter().map(|(_, value)| **value).collect::<Vec<_>>(),
+        expected
+    );
+    for (h, value) in seen.iter() {
+        assert_eq!(*h, handles[**value as usize]);
+    }
+    assert_eq!(buffer.Iter().count(), buffer.Size());
+
+    for value in buffer.IterMut() {
+        *value += 1000;
+    }
+    for (i, h) in handles.iter().enumerate() {
+        let expected = if i % 3 == 0 {
+            None
+        } else {
+            Some(i as u32 + 1000)
+        };
+        assert_eq!(buffer.TryGet(*h).copied(), expected);
+    }
+}
+
+#[test]
+fn retain_frees_rejected_values() {
+    let mut buffer = SparceBuffer::<u32>::new();
+    let handles: Vec<_> = (0..50).map(|i| buffer.Allocate(i)).collect();
+
+    buffer.Retain(|h, value| {
+        assert_eq!(h, handles[*value as usize]);
+        *value *= 10;
+        return *value % 20 == 0;
+    });
+    assert_eq!(buffer.Size(), 25);
+    for (i, h) in handles.iter().enumerate() {
+        let expected = if i % 2 == 0 {
+            Some(i as u32 * 10)
+        } else {
+            None
+        };
+        assert_eq!(buffer.TryGet(*h).copied(), expected);
+    }
+
+    //the freed slots come back under a new generation, the old handles stay stale
+    let reused: Vec<_> = (0..25).map(|i| buffer.Allocate(100 + i)).collect();
+    assert_eq!(buffer.NodeCount(), 2);
+    for h in reused.iter() {
+        let old = *handles
+            .iter()
+            .find(|old| old.Node() == h.Node() && old.Instance() == h.Instance())
+            .unwrap();
+        assert_ne!(h.Generation(), old.Generation());
+        assert_eq!(buffer.TryGet(old), None);
+    }
+}
+
+#[test]
+fn drain_empties_the_buffer() {
+    let mut buffer = SparceBuffer::<u32>::new();
+    let handles: Vec<_> = (0..40).map(|i| buffer.Allocate(i)).collect();
+    buffer.Free(handles[5]);
+
+    let mut drained: Vec<_> = buffer.Drain().collect();
+    drained.sort_by_key(|(_, value)| *value);
+    assert_eq!(drained.len(), 39);
+    for (h, value) in drained.iter() {
+        assert_eq!(*h, handles[*value as usize]);
+    }
+    assert_eq!(buffer.Size(), 0);
+    assert_eq!(buffer.Iter().count(), 0);
+    assert!(handles.iter().all(|h| !buffer.IsValid(*h)));
+
+    //the nodes are kept and filled again
+    let h = buffer.Allocate(7);
+    assert_eq!(buffer[h], 7);
+    assert_eq!(buffer.NodeCount(), 2);
+    assert!(!handles.contains(&h));
+}
+
+#[test]
+fn dropped_drain_still_removes_everything() {
+    let drops = Rc::new(Cell::new(0));
+    let mut buffer = SparceBuffer::<DropCounter>::new();
+    for _ in 0..10 {
+        buffer.Allocate(Counter(&drops));
+    }
+    let first = buffer.Drain().next();
+    assert!(first.is_some());
+    assert_eq!(drops.get(), 9);
+    drop(first);
+    assert_eq!(drops.get(), 10);
+    assert_eq!(buffer.Size(), 0);
+}
+
+//the same workload run under each handle layout
+fn AllocateFreeReuse<L: HandleLayout>() {
+    let mut buffer = SparceBuffer::<String, L>::new();
+    let handles: Vec<_> = (0..100).map(|i| buffer.Allocate(i.to_string())).collect();
+    for h in handles.iter().step_by(2) {
+        buffer.Free(*h);
+    }
+    assert_eq!(buffer.Size(), 50);
+
+    let reused: Vec<_> = (0..50)
+        .map(|i| buffer.Allocate(format!("new {}", i)))
+        .collect();
+    assert_eq!(buffer.NodeCount(), 4);
+    for (i, h) in handles.iter().enumerate() {
+        if i % 2 == 0 {
+            assert_eq!(buffer.TryGet(*h), None);
+        } else {
+            assert_eq!(buffer[*h], i.to_string());
+        }
+    }
+    for (i, h) in reused.iter().enumerate() {
+        assert_eq!(h.Generation(), 2);
+        assert_eq!(buffer[*h], format!("new {}", i));
+    }
+}
+
+#[test]
+fn handle32_buffer() {
+    AllocateFreeReuse::<handle::Handle32>();
+}
+
+#[test]
+fn handle64_buffer() {
+    AllocateFreeReuse::<handle::Handle64>();
+}
+
+#[test]
+fn grows_past_255_nodes() {
+    let buffer = SparceBuffer::<u32>::new();
+    let count = 300 * 32;
+    let handles: Vec<_> = (0..count).map(|i| buffer.Allocate(i)).collect();
+    assert_eq!(buffer.NodeCount(), 300);
+    assert!(handles.iter().any(|h| h.Node() > 255));
+    for (i, h) in handles.iter().enumerate() {
+        assert_eq!(buffer[*h], i as u32);
+    }
+}
+
+#[test]
+fn allocation_fails_once_the_layout_is_full() {
+    //4 nodes of 32
+    let mut buffer = SparceBuffer::<u32, Tiny>::new();
+    let handles: Vec<_> = (0..128).map(|i| buffer.TryAllocate(i).unwrap()).collect();
+    assert_eq!(buffer.TryAllocate(128), Err(AllocError { max_nodes: 4 }));
+    assert_eq!(buffer.Size(), 128);
+
+    //a freed slot makes room again
+    buffer.Free(handles[17]);
+    let h = buffer.TryAllocate(200).unwrap();
+    assert_eq!(buffer[h], 200);
+    assert!(buffer.TryAllocate(201).is_err());
+
+    let capped = SparceBuffer::<u32>::WithMaxNodes(1);
+    for i in 0..32 {
+        capped.Allocate(i);
+    }
+    assert_eq!(capped.TryAllocate(32), Err(AllocError { max_nodes: 1 }));
+}
+
+//decodes the buffer's snapshot, lets corrupt change it and restores the re-encoded bytes
+fn RestoreCorrupted<F>(buffer: &SparceBuffer<u32>, corrupt: F) -> String
+where
+    F: FnOnce(&mut BufferImage<u32>),
+{
+    let mut image = BufferImage::<u32>::Decode(&buffer.Snapshot().unwrap()).unwrap();
+    corrupt(&mut image);
+    return match SparceBuffer::<u32>::Restore(&image.Encode().unwrap()) {
+        Err(SnapshotError::Corrupt(reason)) => reason,
+        Err(err) => panic!("expected a corrupt snapshot, got {}", err),
+        Ok(_) => panic!("corrupt snapshot restored"),
+    };
+}
+
+#[test]
+fn restore_rejects_broken_free_list() {
+    let full = SparceBuffer::<u32>::new();
+    for i in 0..32 {
+        full.Allocate(i);
+    }
+    assert_eq!(full.NodeCount(), 1);
+    let reason = RestoreCorrupted(&full, |image| image.free_list = 0);
+    assert!(
+        reason.contains("full node 0 is on the free list"),
+        "{}",
+        reason
+    );
+
+    let partial = SparceBuffer::<u32>::new();
+    partial.Allocate(1);
+    let reason = RestoreCorrupted(&partial, |image| image.nodes[0].next = 0);
+    assert!(
+        reason.contains("free list loops back to node 0"),
+        "{}",
+        reason
+    );
+}
+
+#[test]
+fn restore_rejects_out_of_range_generations() {
+    let buffer = SparceBuffer::<u32>::new();
+    buffer.Allocate(1);
+    for generation in [0, u32::MAX] {
+        let reason = RestoreCorrupted(&buffer, |image| image.nodes[0].generation[5] = generation);
+        assert!(
+            reason.contains(&format!("generation {} outside", generation)),
+            "{}",
+            reason
+        );
+    }
+    let reason = RestoreCorrupted(&buffer, |image| image.fresh_generation = 0);
+    assert!(reason.contains("generation 0 outside"), "{}", reason);
+}
+
+#[test]
+fn compact_releases_nodes_and_remaps_handles() {
+    let mut buffer = SparceBuffer::<usize>::new();
+    let handles: Vec<_> = (0..200).map(|i| buffer.Allocate(i)).collect();
+    for (i, h) in handles.iter().enumerate() {
+        if i % 5 != 0 {
+            buffer.Free(*h);
+        }
+    }
+    assert_eq!(buffer.Stats().NodeCount(), 7);
+    assert!(buffer.Stats().Fragmentation() > 0.5);
+
+    let remap = buffer.Compact();
+    assert_eq!(buffer.Stats().NodeCount(), 2);
+    assert_eq!(buffer.Stats().Fragmentation(), 0.0);
+    for (i, h) in handles.iter().enumerate().step_by(5) {
+        let moved = *remap.get(h).unwrap_or(h);
+        assert_eq!(buffer[moved], i);
+    }
+    for (old, _) in remap.iter() {
+        assert_eq!(buffer.TryGet(*old), None);
+    }
+}
@@ -0,0 +1,37 @@
+extern crate handle;
+use handle::HandleLayout;
+use std::cell::Cell;
+use std::rc::Rc;
+
+//fixtures shared by the buffer tests
+
+/*
+ * Bumps a shared counter when dropped, so tests can count drops per value
+ */
+pub struct DropCounter {
+    drops: Rc<Cell<usize>>,
+}
+
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        self.drops.set(self.drops.get() + 1);
+    }
+}
+
+pub fn Counter(drops: &Rc<Cell<usize>>) -> DropCounter {
+    return DropCounter {
+        drops: drops.clone(),
+    };
+}
+
+/*
+ * 16 bit handles with room for 4 nodes, to run a buffer out of capacity quickly
+ */
+pub struct Tiny;
+
+impl HandleLayout for Tiny {
+    type Raw = u16;
+    const GENERATION_BITS: u32 = 8;
+    const NODE_BITS: u32 = 2;
+    const INSTANCE_BITS: u32 = 6;
+}
@@ -0,0 +1,119 @@
+extern crate handle;
+extern crate versioned_buffer;
+use std::collections::VecDeque;
+use versioned_buffer::{Commit, Trie, TrieHash, TrieKey, TrieNode};
+
+/*
+ * Keeps the last capacity versions of a Trie, each tagged with the tick it was committed on
+ * Ticks are whatever the caller counts in, e.g. ServerStateUpdate.seq_num
+ *
+ * Every version in the ring holds a snapshot, so its nodes stay alive until it is pruned
+ */
+pub struct TrieHistory<T, K: TrieKey = i64> {
+    trie: Trie<T, K>,
+    history: VecDeque<(u64, handle::handle_t<TrieNode<T, K>>)>,
+    capacity: usize,
+}
+
+impl<T, K> TrieHistory<T, K>
+where
+    T: TrieHash,
+    K: TrieKey,
+{
+    pub fn new(capacity: usize) -> Self {
+        return Self {
+            trie: Trie::new(),
+            history: VecDeque::new(),
+            capacity: capacity.max(1),
+        };
+    }
+
+    /*
+     * The trie all versions live in, for Get, Iter and Diff on the handles At returns
+     */
+    pub fn Trie(&self) -> &Trie<T, K> {
+        return &self.trie;
+    }
+
+    /*
+     * Applies the commit as the state of tick, dropping the oldest version once the ring is full
+     * Ticks have to increase from one commit to the next
+     */
+    pub fn Commit(&mut self, tick: u64, commit: Commit<T, K>) -> handle::handle_t<TrieNode<T, K>> {
+        if let Some(latest) = self.LatestTick() {
+            assert!(
+                tick > latest,
+                "TrieHistory: tick {} committed after tick {}",
+                tick,
+                latest
+            );
+        }
+        self.trie.ApplyCommit(commit);
+        let root = self.trie.Snapshot();
+        self.history.push_back((tick, root));
+
+        while self.history.len() > self.capacity {
+            let (_, oldest) = self.history.pop_front().expect("");
+            self.trie.ReleaseSnapshot(oldest);
+        }
+        return root;
+    }
+
+    /*
+     * The state as of tick, that is the version of the latest commit at or before it
+     * None if tick is older than everything still in the ring
+     * The handle stays valid until that version is pruned or rewound past
+     */
+    pub fn At(&self, tick: u64) -> Option<handle::handle_t<TrieNode<T, K>>> {
+        let index = self.IndexAt(tick)?;
+        return Some(self.history[index].1);
+    }
+
+    /*
+     * Makes the state as of tick current again and forgets every version after it
+     * Returns false, changing nothing, if tick is older than everything in the ring
+     */
+    pub fn Rewind(&mut self, tick: u64) -> bool {
+        let index = match self.IndexAt(tick) {
+            Some(index) => index,
+            None => return false,
+        };
+        while self.history.len() > index + 1 {
+            let (_, newer) = self.history.pop_back().expect("");
+            self.trie.ReleaseSnapshot(newer);
+        }
+        self.trie.Checkout(self.history[index].1);
+        return true;
+    }
+
+    /*
+     * Releases the versions no longer needed to answer At(tick) for any tick from oldest_tick on
+     * The version in effect at oldest_tick is kept, even if it was committed earlier
+     */
+    pub fn Prune(&mut self, oldest_tick: u64) {
+        while self.history.len() > 1 && self.history[1].0 <= oldest_tick {
+            let (_, oldest) = self.history.pop_front().expect("");
+            self.trie.ReleaseSnapshot(oldest);
+        }
+    }
+
+    pub fn OldestTick(&self) -> Option<u64> {
+        return self.history.front().map(|(tick, _)| *tick);
+    }
+
+    pub fn LatestTick(&self) -> Option<u64> {
+        return self.history.back().map(|(tick, _)| *tick);
+    }
+
+    pub fn Len(&self) -> usize {
+        return self.history.len();
+    }
+
+    //index of the last version committed at or before tick, ticks are sorted so this is a binary search
+    fn IndexAt(&self, tick: u64) -> Option<usize> {
+        let after = self
+            .history
+            .partition_point(|(committed, _)| *committed <= tick);
+        return after.checked_sub(1);
+    }
+}
@@ -0,0 +1,81 @@
+extern crate trie_history;
+extern crate versioned_buffer;
+
+use trie_history::TrieHistory;
+use versioned_buffer::Commit;
+
+//entity 1 moves one unit per tick, position is the value
+fn Move(position: u64) -> Commit<u64> {
+    let mut commit = Commit::new();
+    commit.Put(1, position);
+    return commit;
+}
+
+#[test]
+fn at_finds_the_state_of_a_tick() {
+    let mut history = TrieHistory::<u64>::new(8);
+    //ticks 10, 12, 14 ... so some ticks have no commit of their own
+    for step in 0..5 {
+        history.Commit(10 + step * 2, Move(step));
+    }
+
+    let position = |tick| {
+        let root = history.At(tick)?;
+        return history.Trie().Get(root, 1).map(|v| *v);
+    };
+    assert_eq!(position(9), None);
+    assert_eq!(position(10), Some(0));
+    assert_eq!(position(13), Some(1));
+    assert_eq!(position(18), Some(4));
+    assert_eq!(position(1000), Some(4));
+}
+
+#[test]
+fn ring_drops_the_oldest_versions() {
+    let mut history = TrieHistory::<u64>::new(4);
+    for tick in 0..10 {
+        history.Commit(tick, Move(tick));
+    }
+    assert_eq!(history.Len(), 4);
+    assert_eq!(history.OldestTick(), Some(6));
+    assert!(history.At(5).is_none());
+
+    //each held version has its own copy of the path to key 1, nothing older survives
+    assert_eq!(history.Trie().ValueStats().live, 4);
+
+    history.Prune(8);
+    assert_eq!(history.OldestTick(), Some(8));
+    assert_eq!(history.Trie().ValueStats().live, 2);
+}
+
+#[test]
+fn rewind_restores_an_old_tick() {
+    let mut history = TrieHistory::<u64>::new(16);
+    for tick in 0..10 {
+        history.Commit(tick, Move(tick * 10));
+    }
+    //a tick past the latest rewinds to the latest, which changes nothing
+    assert!(history.Rewind(100));
+    assert_eq!(history.LatestTick(), Some(9));
+
+    assert!(history.Rewind(4));
+    assert_eq!(history.LatestTick(), Some(4));
+    let root = history.Trie().Root();
+    assert_eq!(*history.Trie().Get(root, 1).unwrap(), 40);
+
+    //the timeline continues from the rewound state
+    history.Commit(5, Move(41));
+    assert_eq!(*history.Trie().Get(history.At(5).unwrap(), 1).unwrap(), 41);
+    assert_eq!(*history.Trie().Get(history.At(3).unwrap(), 1).unwrap(), 30);
+
+    let mut empty = TrieHistory::<u64>::new(4);
+    assert!(!empty.Rewind(0));
+}
+
+#[test]
+#[should_panic(expected = "committed after")]
+fn ticks_must_increase() {
+    let mut history = TrieHistory::<u64>::new(4);
+    history.Commit(5, Move(0));
+    history.Commit(5, Move(1));
+}
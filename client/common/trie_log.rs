@@ -0,0 +1,324 @@
+extern crate bincode;
+extern crate handle;
+extern crate serde;
+extern crate versioned_buffer;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use versioned_buffer::{Commit, Trie, TrieHash, TrieKey, TrieNode};
+
+/*
+ * Every record is framed as
+ *
+ * kind: u8, RECORD_COMMIT or RECORD_CHECKPOINT
+ * len: u32 little endian, size of the payload
+ * crc: u32 little endian, CRC32 of kind and payload
+ * payload: len bytes of bincode
+ *
+ * A commit payload is the commit's writes, a checkpoint payload every key value pair of a root
+ */
+const RECORD_COMMIT: u8 = 1;
+const RECORD_CHECKPOINT: u8 = 2;
+const HEADER_SIZE: usize = 9;
+
+/*
+ * What Open found in the log
+ * torn_bytes is what was cut off the end, a record the process died in the middle of writing
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recovery {
+    pub checkpoint_loaded: bool,
+    pub commits_replayed: usize,
+    pub torn_bytes: u64,
+}
+
+#[derive(Debug)]
+pub enum LogError {
+    Io(std::io::Error),
+    Encoding(bincode::Error),
+    //a record passed its checksum but is of a kind this version doesn't know,
+    //likely written by a newer build, so Open refuses rather than cut it and what follows
+    UnknownRecord { kind: u8, offset: u64 },
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            LogError::Io(err) => write!(f, "TrieLog io: {}", err),
+            LogError::Encoding(err) => write!(f, "TrieLog encoding: {}", err),
+            LogError::UnknownRecord { kind, offset } => {
+                write!(
+                    f,
+                    "TrieLog unknown record kind {} at offset {}",
+                    kind, offset
+                )
+            }
+        };
+    }
+}
+
+impl std::error::Error for LogError {}
+
+impl From<std::io::Error> for LogError {
+    fn from(err: std::io::Error) -> Self {
+        return LogError::Io(err);
+    }
+}
+
+impl From<bincode::Error> for LogError {
+    fn from(err: bincode::Error) -> Self {
+        return LogError::Encoding(err);
+    }
+}
+
+/*
+ * Append only log of the commits applied to a Trie, so the latest root survives a restart
+ *
+ * Every checkpoint_every commits the full state is written out as a checkpoint,
+ * the log is then replaced by a file holding only that checkpoint so replay stays bounded
+ * The swap goes through a temporary file and a rename, a crash leaves either the old or the new log
+ * A checkpoint that fails during Append doesn't fail the commit, see CheckpointError
+ */
+pub struct TrieLog<T, K: TrieKey = i64> {
+    path: PathBuf,
+    file: File,
+    checkpoint_every: usize,
+    commits_since_checkpoint: usize,
+    checkpoint_error: Option<LogError>,
+    recovery: Recovery,
+    _marker: PhantomData<(T, K)>,
+}
+
+impl<T, K> TrieLog<T, K>
+where
+    T: TrieHash + Serialize + DeserializeOwned,
+    K: TrieKey + Serialize + DeserializeOwned,
+{
+    /*
+     * Opens the log at path, creating it if needed, and rebuilds the trie it describes
+     * A torn or corrupt record ends the log, it and anything after it are truncated away
+     * An intact record of an unknown kind is an error and leaves the file as it is
+     */
+    pub fn Open<P: AsRef<Path>>(
+        path: P,
+        checkpoint_every: usize,
+    ) -> Result<(Self, Trie<T, K>), LogError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut trie = Trie::new();
+        let mut recovery = Recovery::default();
+        let mut offset = 0;
+        while let Some((kind, payload)) = ReadRecord(&bytes[offset..]) {
+            match kind {
+                RECORD_COMMIT => {
+                    let writes: Vec<(K, Option<T>)> = bincode::deserialize(payload)?;
+                    trie.ApplyCommit(CommitFrom(writes));
+                    recovery.commits_replayed += 1;
+                }
+                RECORD_CHECKPOINT => {
+                    //a checkpoint only ever starts a log, but load it over whatever came first anyway
+                    let entries: Vec<(K, T)> = bincode::deserialize(payload)?;
+                    trie = Trie::new();
+                    trie.ApplyCommit(CommitFrom(
+                        entries.into_iter().map(|(k, v)| (k, Some(v))).collect(),
+                    ));
+                    recovery.checkpoint_loaded = true;
+                    recovery.commits_replayed = 0;
+                }
+                _ => {
+                    return Err(LogError::UnknownRecord {
+                        kind: kind,
+                        offset: offset as u64,
+                    })
+                }
+            }
+            offset += HEADER_SIZE + payload.len();
+        }
+
+        recovery.torn_bytes = (bytes.len() - offset) as u64;
+        if recovery.torn_bytes != 0 {
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+
+        let log = Self {
+            path: path,
+            file: file,
+            checkpoint_every: checkpoint_every.max(1),
+            commits_since_checkpoint: recovery.commits_replayed,
+            checkpoint_error: None,
+            recovery: recovery,
+            _marker: PhantomData,
+        };
+        return Ok((log, trie));
+    }
+
+    /*
+     * Writes the commit to disk, then applies it
+     * The record is synced before the trie changes, so an applied commit is never lost
+     * A failed write is cut back off, so later records don't land behind a partial one
+     * Once the commit is written it is applied and Ok is returned, even if the checkpoint
+     * it triggers fails, so a caller never retries a commit that already happened
+     */
+    pub fn Append(
+        &mut self,
+        trie: &mut Trie<T, K>,
+        commit: Commit<T, K>,
+    ) -> Result<handle::handle_t<TrieNode<T, K>>, LogError> {
+        let payload = bincode::serialize(commit.Writes())?;
+        let length = self.file.metadata()?.len();
+        let written = self
+            .file
+            .write_all(&EncodeRecord(RECORD_COMMIT, &payload))
+            .and_then(|_| self.file.sync_data());
+        if let Err(err) = written {
+            self.file.set_len(length)?;
+            return Err(LogError::Io(err));
+        }
+
+        let root = trie.ApplyCommit(commit);
+        self.commits_since_checkpoint += 1;
+        if self.commits_since_checkpoint >= self.checkpoint_every {
+            //the count isn't reset on failure, so the next Append tries again
+            if let Err(err) = self.Checkpoint(trie) {
+                self.checkpoint_error = Some(err);
+            }
+        }
+        return Ok(root);
+    }
+
+    /*
+     * Replaces the log with a single checkpoint of the trie's current root
+     */
+    pub fn Checkpoint(&mut self, trie: &Trie<T, K>) -> Result<(), LogError> {
+        let guards: Vec<_> = trie.Iter(trie.Root()).collect();
+        let entries: Vec<(&K, &T)> = guards.iter().map(|(key, value)| (key, &**value)).collect();
+        let payload = bincode::serialize(&entries)?;
+
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".checkpoint");
+        //opened for appending up front, so once the rename lands it already is the log
+        //and there is no reopen left that could fail and strand appends in the old file
+        let mut temp = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&temp_path)?;
+        temp.set_len(0)?;
+        temp.write_all(&EncodeRecord(RECORD_CHECKPOINT, &payload))?;
+        temp.sync_all()?;
+        std::fs::rename(&temp_path, &self.path)?;
+        self.file = temp;
+        //the rename itself only lasts once the directory is synced,
+        //platforms that can't open a directory as a file skip this
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if let Ok(dir) = File::open(dir) {
+            dir.sync_all()?;
+        }
+
+        self.commits_since_checkpoint = 0;
+        self.checkpoint_error = None;
+        return Ok(());
+    }
+
+    /*
+     * Why the last checkpoint Append attempted failed, cleared by the next one that succeeds
+     * The commits themselves are safe in the log meanwhile, it only grows past checkpoint_every
+     */
+    pub fn CheckpointError(&self) -> Option<&LogError> {
+        return self.checkpoint_error.as_ref();
+    }
+
+    /*
+     * What Open had to do to rebuild the trie
+     */
+    pub fn Recovered(&self) -> &Recovery {
+        return &self.recovery;
+    }
+
+    pub fn CommitsSinceCheckpoint(&self) -> usize {
+        return self.commits_since_checkpoint;
+    }
+}
+
+fn CommitFrom<T, K: TrieKey>(writes: Vec<(K, Option<T>)>) -> Commit<T, K> {
+    let mut commit = Commit::new();
+    for (key, value) in writes.into_iter() {
+        match value {
+            Some(value) => commit.Put(key, value),
+            None => commit.Delete(key),
+        };
+    }
+    return commit;
+}
+
+fn EncodeRecord(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_SIZE + payload.len());
+    record.push(kind);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&Crc32(kind, payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    return record;
+}
+
+//returns the kind and payload of the record bytes start with, None if it is cut short or fails its checksum
+fn ReadRecord(bytes: &[u8]) -> Option<(u8, &[u8])> {
+    if bytes.len() < HEADER_SIZE {
+        return None;
+    }
+    let kind = bytes[0];
+    let len = u32::from_le_bytes(bytes[1..5].try_into().expect("")) as usize;
+    let crc = u32::from_le_bytes(bytes[5..9].try_into().expect(""));
+    let payload = bytes[HEADER_SIZE..].get(..len)?;
+    if Crc32(kind, payload) != crc {
+        return None;
+    }
+    return Some((kind, payload));
+}
+
+/*
+ * CRC32 (IEEE, reflected 0xEDB88320) over the kind byte followed by the payload
+ */
+fn Crc32(kind: u8, payload: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in std::iter::once(&kind).chain(payload.iter()) {
+        crc = CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    return !crc;
+}
+
+const CRC_TABLE: [u32; 256] = CrcTable();
+
+const fn CrcTable() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    return table;
+}
@@ -0,0 +1,218 @@
+extern crate trie_log;
+extern crate versioned_buffer;
+
+use std::path::PathBuf;
+use trie_log::{LogError, TrieLog};
+use versioned_buffer::{Commit, Trie};
+
+/*
+ * A fresh log path per test, removed again when dropped
+ */
+struct TempLog(PathBuf);
+
+impl TempLog {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("trie_log_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        return TempLog(path);
+    }
+}
+
+impl Drop for TempLog {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn Contents(trie: &Trie<String>) -> Vec<(i64, String)> {
+    return trie
+        .Iter(trie.Root())
+        .map(|(key, value)| (key, value.clone()))
+        .collect();
+}
+
+fn Put(key: i64, value: &str) -> Commit<String> {
+    let mut commit = Commit::new();
+    commit.Put(key, value.to_string());
+    return commit;
+}
+
+#[test]
+fn replays_commits_after_reopen() {
+    let log_path = TempLog::new("replay");
+    let expected = {
+        let (mut log, mut trie) = TrieLog::<String>::Open(&log_path.0, 100).unwrap();
+        for key in 0..20 {
+            log.Append(&mut trie, Put(key, &format!("creature {}", key)))
+                .unwrap();
+        }
+        let mut commit = Commit::new();
+        commit.Delete(3).Put(4, "boss".to_string());
+        log.Append(&mut trie, commit).unwrap();
+        Contents(&trie)
+    };
+
+    let (log, trie) = TrieLog::<String>::Open(&log_path.0, 100).unwrap();
+    assert_eq!(Contents(&trie), expected);
+    assert_eq!(log.Recovered().commits_replayed, 21);
+    assert_eq!(log.Recovered().torn_bytes, 0);
+    assert_eq!(trie.RootHash(trie.Root()), {
+        let mut rebuilt = Trie::<String>::new();
+        let mut commit = Commit::new();
+        for (key, value) in expected.iter() {
+            commit.Put(*key, value.clone());
+        }
+        rebuilt.ApplyCommit(commit);
+        rebuilt.RootHash(rebuilt.Root())
+    });
+}
+
+#[test]
+fn checkpoints_bound_replay() {
+    let log_path = TempLog::new("checkpoint");
+    let expected = {
+        let (mut log, mut trie) = TrieLog::<String>::Open(&log_path.0, 8).unwrap();
+        for key in 0..30 {
+            log.Append(&mut trie, Put(key % 11, &format!("tick {}", key)))
+                .unwrap();
+        }
+        assert_eq!(log.CommitsSinceCheckpoint(), 30 % 8);
+        Contents(&trie)
+    };
+
+    let (log, trie) = TrieLog::<String>::Open(&log_path.0, 8).unwrap();
+    assert_eq!(Contents(&trie), expected);
+    assert!(log.Recovered().checkpoint_loaded);
+    assert_eq!(log.Recovered().commits_replayed, 30 % 8);
+}
+
+#[test]
+fn torn_tail_is_dropped() {
+    let log_path = TempLog::new("torn");
+    let expected = {
+        let (mut log, mut trie) = TrieLog::<String>::Open(&log_path.0, 100).unwrap();
+        log.Append(&mut trie, Put(1, "kept")).unwrap();
+        let kept = Contents(&trie);
+        log.Append(&mut trie, Put(2, "torn")).unwrap();
+        kept
+    };
+
+    //the process died halfway through writing the last record
+    let bytes = std::fs::read(&log_path.0).unwrap();
+    std::fs::write(&log_path.0, &bytes[..bytes.len() - 3]).unwrap();
+
+    let size_before = std::fs::metadata(&log_path.0).unwrap().len();
+    {
+        let (mut log, mut trie) = TrieLog::<String>::Open(&log_path.0, 100).unwrap();
+        assert_eq!(Contents(&trie), expected);
+        assert_eq!(log.Recovered().commits_replayed, 1);
+        assert!(log.Recovered().torn_bytes > 0);
+        assert_eq!(
+            std::fs::metadata(&log_path.0).unwrap().len(),
+            size_before - log.Recovered().torn_bytes
+        );
+
+        //appends go after the last good record
+        log.Append(&mut trie, Put(3, "after")).unwrap();
+    }
+    let (_, trie) = TrieLog::<String>::Open(&log_path.0, 100).unwrap();
+    assert_eq!(
+        Contents(&trie),
+        vec![(1, "kept".to_string()), (3, "after".to_string())]
+    );
+}
+
+#[test]
+fn corrupt_tail_fails_checksum() {
+    let log_path = TempLog::new("corrupt");
+    {
+        let (mut log, mut trie) = TrieLog::<String>::Open(&log_path.0, 100).unwrap();
+        log.Append(&mut trie, Put(1, "kept")).unwrap();
+        log.Append(&mut trie, Put(2, "flipped")).unwrap();
+    }
+
+    let mut bytes = std::fs::read(&log_path.0).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x40;
+    std::fs::write(&log_path.0, &bytes).unwrap();
+
+    let (log, trie) = TrieLog::<String>::Open(&log_path.0, 100).unwrap();
+    assert_eq!(Contents(&trie), vec![(1, "kept".to_string())]);
+    assert_eq!(log.Recovered().commits_replayed, 1);
+}
+
+//bitwise CRC32 to frame records by hand, the log's own is table driven
+fn Crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    return !crc;
+}
+
+#[test]
+fn unknown_record_kind_is_an_error() {
+    let log_path = TempLog::new("unknown");
+    {
+        let (mut log, mut trie) = TrieLog::<String>::Open(&log_path.0, 100).unwrap();
+        log.Append(&mut trie, Put(1, "kept")).unwrap();
+    }
+    let good = std::fs::read(&log_path.0).unwrap();
+
+    //an intact record from a newer build, followed by more records
+    let payload = [1u8, 2, 3];
+    let mut bytes = good.clone();
+    bytes.push(7);
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&Crc32(&[7, 1, 2, 3]).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes.extend_from_slice(&good);
+    std::fs::write(&log_path.0, &bytes).unwrap();
+
+    match TrieLog::<String>::Open(&log_path.0, 100) {
+        Err(LogError::UnknownRecord { kind, offset }) => {
+            assert_eq!(kind, 7);
+            assert_eq!(offset, good.len() as u64);
+        }
+        _ => panic!("expected an unknown record error"),
+    }
+    assert_eq!(std::fs::read(&log_path.0).unwrap(), bytes);
+}
+
+#[test]
+fn failed_checkpoint_keeps_the_commit() {
+    let log_path = TempLog::new("failed_checkpoint");
+    let (mut log, mut trie) = TrieLog::<String>::Open(&log_path.0, 2).unwrap();
+    log.Append(&mut trie, Put(1, "first")).unwrap();
+
+    //a directory where the log was makes the checkpoint's rename fail,
+    //the open log file lives on unlinked and still takes the commit
+    std::fs::remove_file(&log_path.0).unwrap();
+    std::fs::create_dir(&log_path.0).unwrap();
+    std::fs::write(log_path.0.join("blocker"), b"").unwrap();
+
+    let root = log.Append(&mut trie, Put(2, "second")).unwrap();
+    assert_eq!(root, trie.Root());
+    assert_eq!(Contents(&trie).len(), 2);
+    assert!(matches!(log.CheckpointError(), Some(LogError::Io(_))));
+    assert_eq!(log.CommitsSinceCheckpoint(), 2);
+
+    //the next Append checkpoints again once the path is free
+    std::fs::remove_dir_all(&log_path.0).unwrap();
+    log.Append(&mut trie, Put(3, "third")).unwrap();
+    assert!(log.CheckpointError().is_none());
+    assert_eq!(log.CommitsSinceCheckpoint(), 0);
+    let expected = Contents(&trie);
+    drop(log);
+
+    let (log, trie) = TrieLog::<String>::Open(&log_path.0, 2).unwrap();
+    assert!(log.Recovered().checkpoint_loaded);
+    assert_eq!(Contents(&trie), expected);
+}
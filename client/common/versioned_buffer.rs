@@ -1,46 +1,242 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::collections::HashSet;
+use std::hash::Hasher;
+use std::ops::{Bound, RangeBounds};
 extern crate handle;
 extern crate sparce_buffer_rc;
 
+/*
+ * A key the trie can index, read one byte per level starting from the most significant
+ *
+ * Byte order has to agree with Ord so Iter, Range and Diff come out in key order,
+ * the signed integers flip their sign bit for this
+ * Protocol Entity ids are plain u64 and can be used as they are
+ */
+pub trait TrieKey: Copy + Ord {
+    //distinct keys must differ somewhere in their first LEVELS bytes
+    const LEVELS: usize;
+
+    fn KeyByte(&self, level: usize) -> u8;
+}
+
+macro_rules! unsigned_trie_key {
+    ($($key:ty),*) => {$(
+        impl TrieKey for $key {
+            const LEVELS: usize = std::mem::size_of::<$key>();
+
+            fn KeyByte(&self, level: usize) -> u8 {
+                return (*self >> ((Self::LEVELS - 1 - level) * 8)) as u8;
+            }
+        }
+    )*};
+}
+
+macro_rules! signed_trie_key {
+    ($($key:ty => $bits:ty),*) => {$(
+        impl TrieKey for $key {
+            const LEVELS: usize = std::mem::size_of::<$key>();
+
+            fn KeyByte(&self, level: usize) -> u8 {
+                let bits = (*self as $bits) ^ (1 << (Self::LEVELS * 8 - 1));
+                return (bits >> ((Self::LEVELS - 1 - level) * 8)) as u8;
+            }
+        }
+    )*};
+}
+
+unsigned_trie_key!(u16, u32, u64);
+signed_trie_key!(i16 => u16, i32 => u32, i64 => u64);
+
+//handles order by their raw value, so they are read as an unsigned integer of the layout's width
+impl<T, L: handle::HandleLayout> TrieKey for handle::handle_t<T, L> {
+    const LEVELS: usize = std::mem::size_of::<L::Raw>();
+
+    fn KeyByte(&self, level: usize) -> u8 {
+        return (self.Bits() >> ((Self::LEVELS - 1 - level) * 8)) as u8;
+    }
+}
+
+/*
+ * A value the trie can hash into its Merkle nodes, see RootHash
+ *
+ * Owned by the trie instead of std Hash so floats can be stored, they hash by their bits
+ * so 0.0 and -0.0 count as different values and a NaN equals itself
+ * Equal values must feed the hasher the same bytes, on every platform
+ *
+ * A blanket impl over std Hash would rule out the float impls, so game types opt in
+ * with trie_hash!, through their std Hash or field by field
+ */
+pub trait TrieHash {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H);
+}
+
+/*
+ * Implements TrieHash for types that don't need a hand written impl
+ *
+ * trie_hash!(std: Type, ...) hashes through the type's std Hash,
+ * fine for anything whose Hash feeds fixed bytes, so no floats, pointers or HashMaps
+ * trie_hash!(Type { field, ... }) hashes the listed fields in order, each must be TrieHash
+ */
+#[macro_export]
+macro_rules! trie_hash {
+    (std: $($value:ty),* $(,)?) => {$(
+        impl $crate::TrieHash for $value {
+            fn HashInto<H: ::std::hash::Hasher>(&self, hasher: &mut H) {
+                ::std::hash::Hash::hash(self, hasher);
+            }
+        }
+    )*};
+    ($value:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::TrieHash for $value {
+            fn HashInto<H: ::std::hash::Hasher>(&self, hasher: &mut H) {
+                $($crate::TrieHash::HashInto(&self.$field, hasher);)*
+            }
+        }
+    };
+}
+
+trie_hash!(std: (), bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, str, String);
+
+impl TrieHash for f32 {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+        hasher.write_u32(self.to_bits());
+    }
+}
+
+impl TrieHash for f64 {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+        hasher.write_u64(self.to_bits());
+    }
+}
+
+impl<T, L: handle::HandleLayout> TrieHash for handle::handle_t<T, L> {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+        hasher.write_u64(self.Bits());
+    }
+}
+
+impl<T: TrieHash + ?Sized> TrieHash for &T {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+        (**self).HashInto(hasher);
+    }
+}
+
+impl<T: TrieHash + ?Sized> TrieHash for Box<T> {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+        (**self).HashInto(hasher);
+    }
+}
+
+impl<T: TrieHash + ?Sized> TrieHash for std::rc::Rc<T> {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+        (**self).HashInto(hasher);
+    }
+}
+
+impl<T: TrieHash> TrieHash for Option<T> {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+        match self {
+            Some(value) => {
+                hasher.write_u8(1);
+                value.HashInto(hasher);
+            }
+            None => hasher.write_u8(0),
+        }
+    }
+}
+
+//the length goes first so [a, b] and [a] followed by b hash apart
+impl<T: TrieHash> TrieHash for [T] {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+        hasher.write_usize(self.len());
+        for value in self {
+            value.HashInto(hasher);
+        }
+    }
+}
+
+impl<T: TrieHash, const N: usize> TrieHash for [T; N] {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+        self[..].HashInto(hasher);
+    }
+}
+
+impl<T: TrieHash> TrieHash for Vec<T> {
+    fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+        self[..].HashInto(hasher);
+    }
+}
+
+macro_rules! tuple_trie_hash {
+    ($(($($name:ident),+)),*) => {$(
+        impl<$($name: TrieHash),+> TrieHash for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn HashInto<H: Hasher>(&self, hasher: &mut H) {
+                let ($($name,)+) = self;
+                $($name.HashInto(hasher);)+
+            }
+        }
+    )*};
+}
+
+tuple_trie_hash!((A), (A, B), (A, B, C), (A, B, C, D));
+
 #[derive(Debug)]
-enum TrieValue<T: Copy + std::default::Default> {
+enum TrieValue<T, K: TrieKey> {
     Empty,
-    Trie(handle::handle_t<TrieNode<T>>),
+    Trie(handle::handle_t<TrieNode<T, K>>),
+    //hash is the content hash of the key and value, see HashLeaf
     Leaf {
-        key: i64,
+        key: K,
         value: handle::handle_t<T>,
+        hash: u64,
     },
 }
 
-impl<T: Copy + std::default::Default> Copy for TrieValue<T> {}
+impl<T, K: TrieKey> Copy for TrieValue<T, K> {}
 
-impl<T: Copy + std::default::Default> Clone for TrieValue<T> {
-    fn clone(&self) -> TrieValue<T> {
+impl<T, K: TrieKey> Clone for TrieValue<T, K> {
+    fn clone(&self) -> TrieValue<T, K> {
         return *self;
     }
 }
 
-impl<T> Default for TrieValue<T>
-where
-    T: Default + Copy,
-{
+impl<T, K: TrieKey> PartialEq for TrieValue<T, K> {
+    fn eq(&self, other: &Self) -> bool {
+        return match (self, other) {
+            (TrieValue::Empty, TrieValue::Empty) => true,
+            (TrieValue::Trie(a), TrieValue::Trie(b)) => a == b,
+            (
+                TrieValue::Leaf {
+                    key: key_a,
+                    value: value_a,
+                    ..
+                },
+                TrieValue::Leaf {
+                    key: key_b,
+                    value: value_b,
+                    ..
+                },
+            ) => key_a == key_b && value_a == value_b,
+            _ => false,
+        };
+    }
+}
+
+impl<T, K: TrieKey> Default for TrieValue<T, K> {
     fn default() -> Self {
         return TrieValue::Empty;
     }
 }
 
 #[derive(Debug)]
-struct TrieNode<T: Copy + std::default::Default> {
-    children: [TrieValue<T>; 256],
-    node_hash: i64,
+pub struct TrieNode<T, K: TrieKey = i64> {
+    children: [TrieValue<T, K>; 256],
+    node_hash: u64,
     level: i64,
 }
 
-impl<T> Default for TrieNode<T>
-where
-    T: Default + Copy,
-{
+impl<T, K: TrieKey> Default for TrieNode<T, K> {
     fn default() -> Self {
         return Self {
             children: std::array::from_fn(|_| TrieValue::default()),
@@ -50,38 +246,185 @@ where
     }
 }
 
-impl<T> TrieNode<T>
-where
-    T: Default + Copy,
-{
+impl<T, K: TrieKey> TrieNode<T, K> {
     //returns the index in the children array this key maps to
-    fn KeyIndex(&self, key: i64) -> i64 {
-        let mask: i64 = 0xFF << self.level * 8;
-        return mask & key;
+    //level 0 looks at the most significant byte
+    fn KeyIndex(&self, key: K) -> i64 {
+        debug_assert!((self.level as usize) < K::LEVELS);
+        return key.KeyByte(self.level as usize) as i64;
     }
 }
 
-impl<T: Copy + std::default::Default> Copy for TrieNode<T> {}
+impl<T, K: TrieKey> Copy for TrieNode<T, K> {}
 
-impl<T: Copy + std::default::Default> Clone for TrieNode<T> {
-    fn clone(&self) -> TrieNode<T> {
+impl<T, K: TrieKey> Clone for TrieNode<T, K> {
+    fn clone(&self) -> TrieNode<T, K> {
         return *self;
     }
 }
 
-pub struct Trie<T: std::default::Default + Copy> {
-    node_allocator: sparce_buffer_rc::SparceBufferRc<TrieNode<T>>,
+/*
+ * Persistent trie, every commit produces a new root that shares untouched nodes with the old one
+ *
+ * Nodes and values live in SparceBufferRc, a node is referenced by each parent pointing at it
+ * and by every snapshot held on it, a value by each leaf pointing at it
+ * The trie itself holds one reference on the current root
+ */
+pub struct Trie<T, K: TrieKey = i64> {
+    node_allocator: sparce_buffer_rc::SparceBufferRc<TrieNode<T, K>>,
     data_allocator: sparce_buffer_rc::SparceBufferRc<T>,
-    root_node: handle::handle_t<TrieNode<T>>,
+    root_node: handle::handle_t<TrieNode<T, K>>,
+}
+
+/*
+ * Walks one snapshot in key order, yielding each key with its value
+ * Keeps a stack of the nodes it is inside of, at most one per level
+ *
+ * Only nodes whose path matches the start of lo or hi need their children clipped,
+ * every other node on the stack lies wholly inside the range
+ */
+pub struct TrieIter<'a, T, K: TrieKey = i64> {
+    trie: &'a Trie<T, K>,
+    stack: Vec<IterFrame<T, K>>,
+    lo: Bound<K>,
+    hi: Bound<K>,
+    prefix: Option<(K, usize)>,
 }
 
-pub struct Commit<T> {
-    pending_writes: Vec<(i64, T)>,
+//index is the next child to visit, end the last one, both inclusive
+struct IterFrame<T, K: TrieKey> {
+    node: handle::handle_t<TrieNode<T, K>>,
+    index: usize,
+    end: usize,
+    on_lo: bool,
+    on_hi: bool,
 }
 
-impl<T> Trie<T>
+impl<'a, T, K: TrieKey> TrieIter<'a, T, K> {
+    fn Push(&mut self, h: handle::handle_t<TrieNode<T, K>>, on_lo: bool, on_hi: bool) {
+        let level = self.trie.node_allocator.Get(h).level as usize;
+        let mut start = 0;
+        let mut end = 255;
+        if let (true, Some(lo)) = (on_lo, BoundKey(&self.lo)) {
+            start = lo.KeyByte(level) as usize;
+        }
+        if let (true, Some(hi)) = (on_hi, BoundKey(&self.hi)) {
+            end = hi.KeyByte(level) as usize;
+        }
+        if let Some((prefix, prefix_bytes)) = self.prefix {
+            if level < prefix_bytes {
+                start = start.max(prefix.KeyByte(level) as usize);
+                end = end.min(prefix.KeyByte(level) as usize);
+            }
+        }
+        self.stack.push(IterFrame {
+            node: h,
+            index: start,
+            end: end,
+            on_lo: on_lo,
+            on_hi: on_hi,
+        });
+    }
+
+    fn Contains(&self, key: &K) -> bool {
+        if let Some((prefix, prefix_bytes)) = self.prefix {
+            if (0..prefix_bytes).any(|level| key.KeyByte(level) != prefix.KeyByte(level)) {
+                return false;
+            }
+        }
+        return (self.lo, self.hi).contains(key);
+    }
+}
+
+impl<'a, T, K: TrieKey> Iterator for TrieIter<'a, T, K> {
+    type Item = (K, sparce_buffer_rc::BufferRef<'a, T>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(frame) = self.stack.last_mut() {
+            if frame.index > frame.end {
+                self.stack.pop();
+                continue;
+            }
+            let index = frame.index;
+            frame.index += 1;
+            let (h, on_lo, on_hi) = (frame.node, frame.on_lo, frame.on_hi);
+
+            let node = self.trie.node_allocator.Get(h);
+            match node.children[index] {
+                TrieValue::Empty => {}
+                TrieValue::Trie(child) => {
+                    //a child stays on a boundary only if it sits at that boundary's byte
+                    let level = node.level as usize;
+                    let child_on_lo = on_lo
+                        && BoundKey(&self.lo).map(|k| k.KeyByte(level) as usize) == Some(index);
+                    let child_on_hi = on_hi
+                        && BoundKey(&self.hi).map(|k| k.KeyByte(level) as usize) == Some(index);
+                    drop(node);
+                    self.Push(child, child_on_lo, child_on_hi);
+                }
+                TrieValue::Leaf { key, value, .. } => {
+                    if self.Contains(&key) {
+                        return Some((key, self.trie.data_allocator.Get(value)));
+                    }
+                }
+            }
+        }
+        return None;
+    }
+}
+
+/*
+ * A batch of writes applied to the trie as one new version
+ * Writes are applied in the order they were added, so a later write to a key wins
+ */
+pub struct Commit<T, K: TrieKey = i64> {
+    pending_writes: Vec<(K, Option<T>)>,
+}
+
+impl<T, K: TrieKey> Commit<T, K> {
+    pub fn new() -> Self {
+        return Self {
+            pending_writes: Vec::new(),
+        };
+    }
+
+    pub fn Put(&mut self, key: K, value: T) -> &mut Self {
+        self.pending_writes.push((key, Some(value)));
+        return self;
+    }
+
+    /*
+     * Deleting a key that isn't in the trie is a no-op
+     */
+    pub fn Delete(&mut self, key: K) -> &mut Self {
+        self.pending_writes.push((key, None));
+        return self;
+    }
+
+    /*
+     * The writes in the order they will be applied, None is a delete
+     */
+    pub fn Writes(&self) -> &[(K, Option<T>)] {
+        return &self.pending_writes;
+    }
+
+    pub fn Len(&self) -> usize {
+        return self.pending_writes.len();
+    }
+
+    pub fn IsEmpty(&self) -> bool {
+        return self.pending_writes.is_empty();
+    }
+}
+
+/*
+ * Values can be any owned type that implements TrieHash, they are moved in by Commit and never copied
+ * TrieHash feeds the Merkle node hashes, see RootHash, trie_hash! covers most types
+ */
+impl<T, K> Trie<T, K>
 where
-    T: Default + Copy,
+    T: TrieHash,
+    K: TrieKey,
 {
     pub fn new() -> Self {
         let mut result = Self {
@@ -90,37 +433,230 @@ where
             data_allocator: sparce_buffer_rc::SparceBufferRc::new(),
         };
 
-        result.root_node = result.node_allocator.Allocate(TrieNode::<T>::default());
+        result.root_node = result.node_allocator.Allocate(TrieNode::default());
+        result.RehashImpl(result.root_node, &HashSet::new());
         return result;
     }
 
-    pub fn ApplyCommit(&mut self, commit: Commit<T>) -> handle::handle_t<TrieNode<T>> {
+    /*
+     * Copies the path to every key in the commit, writes into the copies and makes the result the new root
+     * Nodes off those paths are shared with the previous root
+     *
+     * The previous root is released, its nodes and values live on only if a snapshot still holds them
+     * The returned root is not held, take a Snapshot to keep it past the next commit
+     */
+    pub fn ApplyCommit(&mut self, commit: Commit<T, K>) -> handle::handle_t<TrieNode<T, K>> {
         let new_root = self.CopyNode(self.root_node);
         let mut inserted_nodes = HashSet::new();
-        let mut removed_nodes = HashSet::new();
-
         inserted_nodes.insert(new_root);
-        removed_nodes.insert(self.root_node);
 
-        for (key, value) in commit.pending_writes.iter() {
-            self.CopyPathImpl(*key, new_root, &mut inserted_nodes, &mut removed_nodes);
+        for (key, _) in commit.pending_writes.iter() {
+            self.CopyPathImpl(*key, new_root, &mut inserted_nodes);
         }
-        for (key, value) in commit.pending_writes.iter() {
-            self.WriteValueImpl(*key, *value, new_root);
+        for (key, value) in commit.pending_writes.into_iter() {
+            match value {
+                Some(value) => self.WriteValueImpl(key, value, new_root, &mut inserted_nodes),
+                None => self.DeleteValueImpl(key, new_root),
+            }
         }
+        self.RehashImpl(new_root, &inserted_nodes);
+
+        self.ReleaseNode(self.root_node);
+        self.root_node = new_root;
+        return new_root;
+    }
+
+    /*
+     * Looks key up in the version rooted at snapshot
+     */
+    pub fn Get(
+        &self,
+        snapshot: handle::handle_t<TrieNode<T, K>>,
+        key: K,
+    ) -> Option<sparce_buffer_rc::BufferRef<'_, T>> {
+        let (value, _) = self.FindLeaf(snapshot, key)?;
+        return Some(self.data_allocator.Get(value));
+    }
+
+    /*
+     * Folds two commits built off the same base snapshot into one
+     *
+     * Within each commit the last write to a key wins, keys only one side wrote go through as is
+     * Keys both sides wrote are settled without the resolver when the writes agree,
+     * or when one side wrote back what base already held, the other side wins then
+     * Everything else goes to resolver(key, base, ours, theirs), None meaning absent or deleted,
+     * and the merged commit writes whatever it returns
+     *
+     * Writes are compared by content hash, see RootHash
+     */
+    pub fn Merge<F>(
+        &self,
+        base: handle::handle_t<TrieNode<T, K>>,
+        ours: Commit<T, K>,
+        theirs: Commit<T, K>,
+        mut resolver: F,
+    ) -> Commit<T, K>
+    where
+        F: FnMut(K, Option<&T>, Option<T>, Option<T>) -> Option<T>,
+    {
+        let mut theirs: BTreeMap<K, Option<T>> = theirs.pending_writes.into_iter().collect();
+        let ours: BTreeMap<K, Option<T>> = ours.pending_writes.into_iter().collect();
+        let mut merged = Commit::new();
 
-        for node : removed_nodes.iter() {
-            self.node_allocator.Free(node);
+        for (key, our_value) in ours.into_iter() {
+            let their_value = match theirs.remove(&key) {
+                Some(their_value) => their_value,
+                None => {
+                    merged.pending_writes.push((key, our_value));
+                    continue;
+                }
+            };
+
+            let base_hash = self.FindLeaf(base, key).map(|(_, hash)| hash);
+            let our_hash = our_value.as_ref().map(|value| HashLeaf(key, value));
+            let their_hash = their_value.as_ref().map(|value| HashLeaf(key, value));
+            let value = if our_hash == their_hash || their_hash == base_hash {
+                our_value
+            } else if our_hash == base_hash {
+                their_value
+            } else {
+                let base_value = self.Get(base, key);
+                resolver(key, base_value.as_deref(), our_value, their_value)
+            };
+            merged.pending_writes.push((key, value));
         }
+        merged.pending_writes.extend(theirs.into_iter());
+        return merged;
+    }
 
-        return new_root;
+    /*
+     * Resolves a value handle handed out by Diff
+     */
+    pub fn GetValue(&self, h: handle::handle_t<T>) -> sparce_buffer_rc::BufferRef<'_, T> {
+        return self.data_allocator.Get(h);
+    }
+
+    /*
+     * Every key in the snapshot, in ascending order
+     */
+    pub fn Iter(&self, snapshot: handle::handle_t<TrieNode<T, K>>) -> TrieIter<'_, T, K> {
+        return self.Range(snapshot, ..);
+    }
+
+    /*
+     * Keys in range, in ascending order
+     * Only the nodes on the two boundary paths and inside the range are visited
+     */
+    pub fn Range<R>(
+        &self,
+        snapshot: handle::handle_t<TrieNode<T, K>>,
+        range: R,
+    ) -> TrieIter<'_, T, K>
+    where
+        R: RangeBounds<K>,
+    {
+        return self.Scan(
+            snapshot,
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+            None,
+        );
     }
 
-    pub fn Snapshot(&self) -> handle::handle_t<TrieNode<T>> {
+    /*
+     * Keys whose first prefix_bytes key bytes match those of prefix, in ascending order
+     * e.g. Prefix(snapshot, kind << 56, 1) for every u64 key of one entity kind
+     * A prefix_bytes past the key's length is clamped to it, matching prefix alone
+     */
+    pub fn Prefix(
+        &self,
+        snapshot: handle::handle_t<TrieNode<T, K>>,
+        prefix: K,
+        prefix_bytes: usize,
+    ) -> TrieIter<'_, T, K> {
+        return self.Scan(
+            snapshot,
+            Bound::Unbounded,
+            Bound::Unbounded,
+            Some((prefix, prefix_bytes.min(K::LEVELS))),
+        );
+    }
+
+    fn Scan(
+        &self,
+        snapshot: handle::handle_t<TrieNode<T, K>>,
+        lo: Bound<K>,
+        hi: Bound<K>,
+        prefix: Option<(K, usize)>,
+    ) -> TrieIter<'_, T, K> {
+        let mut result = TrieIter {
+            trie: self,
+            stack: Vec::new(),
+            lo: lo,
+            hi: hi,
+            prefix: prefix,
+        };
+        if !snapshot.IsNull() {
+            result.Push(snapshot, true, true);
+        }
+        return result;
+    }
+
+    /*
+     * Content hash of the whole snapshot, equal roots hold the same keys and values
+     * A null snapshot hashes the same as an empty trie
+     */
+    pub fn RootHash(&self, snapshot: handle::handle_t<TrieNode<T, K>>) -> u64 {
+        if snapshot.IsNull() {
+            return NodeHasher::new().finish();
+        }
+        return self.node_allocator.Get(snapshot).node_hash;
+    }
+
+    /*
+     * The current root, only valid until the next ApplyCommit
+     */
+    pub fn Root(&self) -> handle::handle_t<TrieNode<T, K>> {
+        return self.root_node;
+    }
+
+    /*
+     * Holds the current root so it can still be read after later commits
+     * Every snapshot must be given back through ReleaseSnapshot
+     */
+    pub fn Snapshot(&self) -> handle::handle_t<TrieNode<T, K>> {
+        self.node_allocator.BumpRef(self.root_node);
         return self.root_node;
     }
 
-    pub fn ReleaseSnapshot(&self, h: handle::handle_t<TrieNode<T>>) {}
+    /*
+     * Makes a snapshot the current root again, the next commit builds on it
+     * The caller's hold on snapshot is left as it was
+     */
+    pub fn Checkout(&mut self, snapshot: handle::handle_t<TrieNode<T, K>>) {
+        self.node_allocator.BumpRef(snapshot);
+        self.ReleaseNode(self.root_node);
+        self.root_node = snapshot;
+    }
+
+    /*
+     * Nodes and values only this snapshot still referenced are reclaimed
+     * Takes &mut self so no value guard from Get can still be reading one of them
+     */
+    pub fn ReleaseSnapshot(&mut self, h: handle::handle_t<TrieNode<T, K>>) {
+        self.ReleaseNode(h);
+    }
+
+    /*
+     * Occupancy of the node and value buffers across every version still alive
+     */
+    pub fn NodeStats(&self) -> handle::BufferStats {
+        return self.node_allocator.Stats();
+    }
+
+    pub fn ValueStats(&self) -> handle::BufferStats {
+        return self.data_allocator.Stats();
+    }
 
     /*
      * Find the difference between the state of two trie nodes
@@ -131,56 +667,135 @@ where
      */
     pub fn Diff<F>(
         &self,
-        a: handle::handle_t<TrieNode<T>>,
-        b: handle::handle_t<TrieNode<T>>,
+        a: handle::handle_t<TrieNode<T, K>>,
+        b: handle::handle_t<TrieNode<T, K>>,
         mut cb: F,
     ) where
-        F: FnMut(i64, handle::handle_t<T>, handle::handle_t<T>),
+        F: FnMut(K, handle::handle_t<T>, handle::handle_t<T>),
     {
+        self.DiffValues(RootValue(a), RootValue(b), &mut cb);
+    }
+
+    //returns the value handle and content hash stored for key
+    fn FindLeaf(
+        &self,
+        snapshot: handle::handle_t<TrieNode<T, K>>,
+        key: K,
+    ) -> Option<(handle::handle_t<T>, u64)> {
+        let mut value = RootValue(snapshot);
+        loop {
+            match value {
+                TrieValue::Empty => return None,
+                TrieValue::Trie(h) => value = self.GetKeyValue(key, h),
+                TrieValue::Leaf {
+                    key: leaf_key,
+                    value: leaf_value,
+                    hash,
+                } => {
+                    if leaf_key != key {
+                        return None;
+                    }
+                    return Some((leaf_value, hash));
+                }
+            }
+        }
     }
 
     /*
-     * Returns a new root that contains the same data but a new set of nodes on the path to key
+     * Walks both sides in step and only descends where they differ
+     * Nodes shared between the two roots, or with the same hash, are skipped whole
+     *
+     * A leaf facing a node is pushed down into the slot its key maps to,
+     * so both sides stay aligned. Absent values are passed as null handles
      */
-    fn CopyPath(
-        &self,
-        key: i64,
-        inserted_nodes: &mut HashSet<handle::handle_t<TrieNode<T>>>,
-        removed_nodes: &mut HashSet<handle::handle_t<TrieNode<T>>>,
-    ) -> handle::handle_t<TrieNode<T>> {
-        let new_root = self.CopyNode(self.root_node);
-        inserted_nodes.insert(new_root);
-        removed_nodes.insert(self.root_node);
-        self.CopyPathImpl(key, new_root, inserted_nodes, removed_nodes);
-        return new_root;
+    fn DiffValues<F>(&self, a: TrieValue<T, K>, b: TrieValue<T, K>, cb: &mut F)
+    where
+        F: FnMut(K, handle::handle_t<T>, handle::handle_t<T>),
+    {
+        if a == b {
+            return;
+        }
+        match (a, b) {
+            (TrieValue::Trie(node_a), TrieValue::Trie(node_b)) => {
+                let node_a = self.node_allocator.Get(node_a);
+                let node_b = self.node_allocator.Get(node_b);
+                if node_a.node_hash == node_b.node_hash {
+                    return;
+                }
+                for index in 0..node_a.children.len() {
+                    self.DiffValues(node_a.children[index], node_b.children[index], cb);
+                }
+            }
+            (TrieValue::Trie(node_a), other) => {
+                let node_a = self.node_allocator.Get(node_a);
+                for index in 0..node_a.children.len() {
+                    self.DiffValues(node_a.children[index], PushDown(&node_a, other, index), cb);
+                }
+            }
+            (other, TrieValue::Trie(node_b)) => {
+                let node_b = self.node_allocator.Get(node_b);
+                for index in 0..node_b.children.len() {
+                    self.DiffValues(PushDown(&node_b, other, index), node_b.children[index], cb);
+                }
+            }
+            (
+                TrieValue::Leaf {
+                    key: key_a,
+                    value: value_a,
+                    hash: hash_a,
+                },
+                TrieValue::Leaf {
+                    key: key_b,
+                    value: value_b,
+                    hash: hash_b,
+                },
+            ) => {
+                if key_a == key_b {
+                    //rewritten with an equal value
+                    if hash_a != hash_b {
+                        cb(key_a, value_a, value_b);
+                    }
+                } else if key_a < key_b {
+                    cb(key_a, value_a, handle::handle_t::null());
+                    cb(key_b, handle::handle_t::null(), value_b);
+                } else {
+                    cb(key_b, handle::handle_t::null(), value_b);
+                    cb(key_a, value_a, handle::handle_t::null());
+                }
+            }
+            (TrieValue::Leaf { key, value, .. }, TrieValue::Empty) => {
+                cb(key, value, handle::handle_t::null());
+            }
+            (TrieValue::Empty, TrieValue::Leaf { key, value, .. }) => {
+                cb(key, handle::handle_t::null(), value);
+            }
+            (TrieValue::Empty, TrieValue::Empty) => {}
+        }
     }
 
     //We assume the parent coming into this function is the copied parent so it can be mutated in place
     //To update the pointer for the key
     fn CopyPathImpl(
         &self,
-        key: i64,
-        parent: handle::handle_t<TrieNode<T>>,
-        copied_nodes: &mut HashSet<handle::handle_t<TrieNode<T>>>,
-        removed_nodes: &mut HashSet<handle::handle_t<TrieNode<T>>>,
+        key: K,
+        parent: handle::handle_t<TrieNode<T, K>>,
+        copied_nodes: &mut HashSet<handle::handle_t<TrieNode<T, K>>>,
     ) {
-        let index = self.GetKeyIndex(key, parent);
         match self.GetKeyValue(key, parent) {
-            TrieValue::Empty => {} //no key found, should not happen
+            TrieValue::Empty => {} //no key found, the write will fill the slot in the copied parent
             TrieValue::Trie(h) => {
+                //an earlier key in this commit may have copied this node already
+                let mut self_copy = h;
                 if (!copied_nodes.contains(&h)) {
-                    let self_copy = self.CopyNode(h);
+                    self_copy = self.CopyNode(h);
                     copied_nodes.insert(self_copy);
-                    removed_nodes.insert(h);
                     self.WriteKeyValue(key, parent, TrieValue::Trie(self_copy));
+                    //the copied parent no longer points at the original
+                    self.ReleaseNode(h);
                 }
-                let self_copy = copied_nodes.get(&h).expect("");
-                self.CopyPathImpl(key, *self_copy, copied_nodes, removed_nodes);
+                self.CopyPathImpl(key, self_copy, copied_nodes);
             }
-            TrieValue::Leaf {
-                key: leaf_key,
-                value,
-            } => {} //found the key, stop iteration
+            TrieValue::Leaf { .. } => {} //found the key, stop iteration
         }
     }
 
@@ -188,80 +803,286 @@ where
     //You should have copied the tree with CopyPathImpl first and the wrote into the copy
     //This will traverse down to the Leaf and write the value in place
     //If the leaf has another key written in it, it will make a new Node and move both keys into it
-    fn WriteValueImpl(&self, key: i64, value: T, parent: handle::handle_t<TrieNode<T>>) {
-        let index = self.node_allocator.Get(parent).KeyIndex(key) as usize;
-        match self.node_allocator.Get(parent).children[index] {
+    //Nodes made by the split are added to inserted_nodes so they get hashed
+    fn WriteValueImpl(
+        &self,
+        key: K,
+        value: T,
+        parent: handle::handle_t<TrieNode<T, K>>,
+        inserted_nodes: &mut HashSet<handle::handle_t<TrieNode<T, K>>>,
+    ) {
+        match self.GetKeyValue(key, parent) {
             TrieValue::Empty => {
+                let hash = HashLeaf(key, &value);
                 let new_value = self.data_allocator.Allocate(value);
                 self.WriteKeyValue(
                     key,
                     parent,
-                    TrieValue::<T>::Leaf {
+                    TrieValue::Leaf {
                         key: key,
                         value: new_value,
+                        hash: hash,
                     },
                 );
             }
             TrieValue::Trie(h) => {
-                self.WriteValueImpl(key, value, h);
+                self.WriteValueImpl(key, value, h, inserted_nodes);
             }
             TrieValue::Leaf {
                 key: leaf_key,
                 value: leaf_value,
+                hash: leaf_hash,
             } => {
                 //found they key, it is either us and we over-write it
                 //or its a differnet value and we need to make a new node and split it
                 if (key == leaf_key) {
+                    let hash = HashLeaf(key, &value);
                     let new_value = self.data_allocator.Allocate(value);
-                    let leaf = TrieValue::<T>::Leaf {
+                    let leaf = TrieValue::Leaf {
                         key: key,
                         value: new_value,
+                        hash: hash,
                     };
                     self.WriteKeyValue(key, parent, leaf);
+                    self.data_allocator.Free(leaf_value);
                 } else {
                     //when making the new node both keys may land in the same index again,
                     //so we must keep recursively calling this
-                    let new_child = self.node_allocator.Allocate(TrieNode::<T>::default());
-                    self.node_allocator.Get(new_child).level =
+                    let new_child = self.node_allocator.Allocate(TrieNode::default());
+                    self.node_allocator.GetMut(new_child).level =
                         self.node_allocator.Get(parent).level + 1;
+                    inserted_nodes.insert(new_child);
 
                     self.WriteKeyValue(key, parent, TrieValue::Trie(new_child));
                     self.WriteKeyValue(
-                        key,
+                        leaf_key,
                         new_child,
-                        TrieValue::<T>::Leaf {
+                        TrieValue::Leaf {
                             key: leaf_key,
                             value: leaf_value,
+                            hash: leaf_hash,
                         },
                     );
-                    self.WriteValueImpl(key, value, new_child);
+                    self.WriteValueImpl(key, value, new_child, inserted_nodes);
                 }
             }
         }
     }
 
+    //Clears the leaf for key in place, same as WriteValueImpl the path must have been copied first
+    //On the way back up every node left holding a single leaf is folded into its parent
+    fn DeleteValueImpl(&self, key: K, parent: handle::handle_t<TrieNode<T, K>>) {
+        match self.GetKeyValue(key, parent) {
+            TrieValue::Empty => {}
+            TrieValue::Trie(h) => {
+                self.DeleteValueImpl(key, h);
+                self.CollapseNode(key, parent, h);
+            }
+            TrieValue::Leaf {
+                key: leaf_key,
+                value: leaf_value,
+                ..
+            } => {
+                if (key == leaf_key) {
+                    self.WriteKeyValue(key, parent, TrieValue::Empty);
+                    self.data_allocator.Free(leaf_value);
+                }
+            }
+        }
+    }
+
+    //Replaces child with its only remaining leaf, or with Empty once nothing is left in it
+    //Nodes holding another node or more than one leaf are left alone
+    fn CollapseNode(
+        &self,
+        key: K,
+        parent: handle::handle_t<TrieNode<T, K>>,
+        child: handle::handle_t<TrieNode<T, K>>,
+    ) {
+        let mut remaining = TrieValue::Empty;
+        {
+            let node = self.node_allocator.Get(child);
+            for value in node.children.iter() {
+                match value {
+                    TrieValue::Empty => {}
+                    TrieValue::Trie(_) => return,
+                    TrieValue::Leaf { .. } => {
+                        if remaining != TrieValue::Empty {
+                            return;
+                        }
+                        remaining = *value;
+                    }
+                }
+            }
+        }
+
+        //the leaf moves up, clear it first so releasing the node keeps the value alive
+        if let TrieValue::Leaf { key: leaf_key, .. } = remaining {
+            self.WriteKeyValue(leaf_key, child, TrieValue::Empty);
+        }
+        self.WriteKeyValue(key, parent, remaining);
+        self.ReleaseNode(child);
+    }
+
+    //Recomputes node_hash bottom up, only descending into nodes written by this commit
+    //Everything else is shared with an older root and already hashed
+    fn RehashImpl(
+        &self,
+        h: handle::handle_t<TrieNode<T, K>>,
+        inserted_nodes: &HashSet<handle::handle_t<TrieNode<T, K>>>,
+    ) {
+        let mut hasher = NodeHasher::new();
+        {
+            let node = self.node_allocator.Get(h);
+            for (index, child) in node.children.iter().enumerate() {
+                match child {
+                    TrieValue::Empty => {}
+                    TrieValue::Trie(child) => {
+                        if inserted_nodes.contains(child) {
+                            self.RehashImpl(*child, inserted_nodes);
+                        }
+                        hasher.write(&[index as u8, 1]);
+                        hasher.write_u64(self.node_allocator.Get(*child).node_hash);
+                    }
+                    TrieValue::Leaf { hash, .. } => {
+                        hasher.write(&[index as u8, 2]);
+                        hasher.write_u64(*hash);
+                    }
+                }
+            }
+        }
+        self.node_allocator.GetMut(h).node_hash = hasher.finish();
+    }
+
     /*
      * Given a node, allocate a new Node and copy all the pointers/values into it
+     * The copy is one more parent for everything the node points at
      */
-    fn CopyNode(&self, h: handle::handle_t<TrieNode<T>>) -> handle::handle_t<TrieNode<T>> {
-        let new_node = self.node_allocator.Allocate(*self.node_allocator.Get(h));
+    fn CopyNode(&self, h: handle::handle_t<TrieNode<T, K>>) -> handle::handle_t<TrieNode<T, K>> {
+        let node = *self.node_allocator.Get(h);
+        for child in node.children.iter() {
+            match child {
+                TrieValue::Empty => {}
+                TrieValue::Trie(child) => self.node_allocator.BumpRef(*child),
+                TrieValue::Leaf { value, .. } => self.data_allocator.BumpRef(*value),
+            }
+        }
+        let new_node = self.node_allocator.Allocate(node);
         return new_node;
     }
 
-    fn WriteKeyValue(&self, key: i64, parent: handle::handle_t<TrieNode<T>>, value: TrieValue<T>) {
-        let mut node = self.node_allocator.Get(parent);
+    /*
+     * Drops one reference to a node, the last one releases everything it points at
+     */
+    fn ReleaseNode(&self, h: handle::handle_t<TrieNode<T, K>>) {
+        if let Some(node) = self.node_allocator.Unref(h) {
+            for child in node.children.iter() {
+                match child {
+                    TrieValue::Empty => {}
+                    TrieValue::Trie(child) => self.ReleaseNode(*child),
+                    TrieValue::Leaf { value, .. } => self.data_allocator.Free(*value),
+                }
+            }
+        }
+    }
+
+    fn WriteKeyValue(
+        &self,
+        key: K,
+        parent: handle::handle_t<TrieNode<T, K>>,
+        value: TrieValue<T, K>,
+    ) {
+        let mut node = self.node_allocator.GetMut(parent);
         let index = node.KeyIndex(key) as usize;
         node.children[index] = value;
     }
 
-    fn GetKeyValue(&self, key: i64, parent: handle::handle_t<TrieNode<T>>) -> TrieValue<T> {
-        let mut node = self.node_allocator.Get(parent);
+    fn GetKeyValue(&self, key: K, parent: handle::handle_t<TrieNode<T, K>>) -> TrieValue<T, K> {
+        let node = self.node_allocator.Get(parent);
         let index = node.KeyIndex(key) as usize;
         return node.children[index];
     }
+}
 
-    fn GetKeyIndex(&self, key: i64, parent: handle::handle_t<TrieNode<T>>) -> i64 {
-        let node = self.node_allocator.Get(parent);
-        return node.KeyIndex(key);
+//a null root is treated as an empty trie
+fn RootValue<T, K: TrieKey>(root: handle::handle_t<TrieNode<T, K>>) -> TrieValue<T, K> {
+    if root.IsNull() {
+        return TrieValue::Empty;
+    }
+    return TrieValue::Trie(root);
+}
+
+//what a leaf or empty slot one level up looks like from child slot index of node
+fn PushDown<T, K: TrieKey>(
+    node: &TrieNode<T, K>,
+    value: TrieValue<T, K>,
+    index: usize,
+) -> TrieValue<T, K> {
+    if let TrieValue::Leaf { key, .. } = value {
+        if node.KeyIndex(key) as usize == index {
+            return value;
+        }
+    }
+    return TrieValue::Empty;
+}
+
+fn BoundKey<K>(bound: &Bound<K>) -> Option<&K> {
+    return match bound {
+        Bound::Included(key) | Bound::Excluded(key) => Some(key),
+        Bound::Unbounded => None,
+    };
+}
+
+//content hash of one key value pair, what node hashes are built from
+//the key goes in through its trie bytes so it hashes the same on every platform
+fn HashLeaf<T: TrieHash, K: TrieKey>(key: K, value: &T) -> u64 {
+    let mut hasher = NodeHasher::new();
+    for level in 0..K::LEVELS {
+        hasher.write_u8(key.KeyByte(level));
+    }
+    value.HashInto(&mut hasher);
+    return hasher.finish();
+}
+
+/*
+ * 64 bit FNV-1a
+ * Unlike the std hasher it is fixed, so the client and server agree on node hashes
+ * Integers are fed little endian whatever the platform
+ */
+struct NodeHasher(u64);
+
+impl NodeHasher {
+    fn new() -> Self {
+        return NodeHasher(0xcbf2_9ce4_8422_2325);
+    }
+}
+
+impl Hasher for NodeHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u16(&mut self, value: u16) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    //usize is widened so 32 and 64 bit builds agree
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        return self.0;
     }
 }
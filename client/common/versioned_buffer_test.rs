@@ -0,0 +1,554 @@
+extern crate handle;
+extern crate versioned_buffer;
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use versioned_buffer::{trie_hash, Commit, Trie};
+
+/*
+ * xorshift, so the random sequences are the same on every run
+ */
+struct Rng(u64);
+
+impl Rng {
+    fn Next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        return self.0;
+    }
+
+    //mixes dense ranges with the far ends of i64 so paths share prefixes and split deep
+    fn Key(&mut self) -> i64 {
+        let offset = (self.Next() % 512) as i64;
+        return match self.Next() % 4 {
+            0 => offset,
+            1 => -offset,
+            2 => 0x0300_0000_0000_0000 | (offset << 20),
+            _ => i64::MIN + offset,
+        };
+    }
+}
+
+fn AssertMatches(trie: &Trie<u64>, model: &BTreeMap<i64, u64>) {
+    let root = trie.Root();
+    for (key, value) in model.iter() {
+        assert_eq!(
+            trie.Get(root, *key).map(|v| *v),
+            Some(*value),
+            "key {}",
+            key
+        );
+    }
+
+    //diffing against a null root lists every key, in key order
+    let mut seen = Vec::new();
+    trie.Diff(handle::handle_t::null(), root, |key, old, new| {
+        assert!(old.IsNull());
+        seen.push((key, *trie.GetValue(new)));
+    });
+    let expected: Vec<_> = model.iter().map(|(k, v)| (*k, *v)).collect();
+    assert_eq!(seen, expected);
+}
+
+#[test]
+fn put_get_delete() {
+    let mut trie = Trie::<u64>::new();
+    let mut commit = Commit::new();
+    commit.Put(1, 10).Put(257, 20).Put(-1, 30).Put(1, 11);
+    trie.ApplyCommit(commit);
+
+    let root = trie.Root();
+    assert_eq!(*trie.Get(root, 1).unwrap(), 11);
+    assert_eq!(*trie.Get(root, 257).unwrap(), 20);
+    assert_eq!(*trie.Get(root, -1).unwrap(), 30);
+    assert!(trie.Get(root, 2).is_none());
+
+    let mut commit = Commit::new();
+    commit.Delete(257).Delete(1000);
+    trie.ApplyCommit(commit);
+    assert!(trie.Get(trie.Root(), 257).is_none());
+    assert_eq!(*trie.Get(trie.Root(), 1).unwrap(), 11);
+}
+
+#[test]
+fn matches_btree_model() {
+    let mut rng = Rng(0x2545F4914F6CDD1D);
+    let mut trie = Trie::<u64>::new();
+    let mut model = BTreeMap::new();
+
+    for _ in 0..200 {
+        let mut commit = Commit::new();
+        for _ in 0..(rng.Next() % 32) {
+            let key = rng.Key();
+            if rng.Next() % 3 == 0 {
+                commit.Delete(key);
+                model.remove(&key);
+            } else {
+                let value = rng.Next();
+                commit.Put(key, value);
+                model.insert(key, value);
+            }
+        }
+        trie.ApplyCommit(commit);
+        AssertMatches(&trie, &model);
+
+        for _ in 0..16 {
+            let key = rng.Key();
+            assert_eq!(
+                trie.Get(trie.Root(), key).map(|v| *v),
+                model.get(&key).copied()
+            );
+        }
+    }
+}
+
+#[test]
+fn snapshots_outlive_commits() {
+    let mut rng = Rng(0x9E3779B97F4A7C15);
+    let mut trie = Trie::<u64>::new();
+    let mut model = BTreeMap::new();
+    let mut held = Vec::new();
+
+    for _ in 0..50 {
+        let mut commit = Commit::new();
+        for _ in 0..(rng.Next() % 16) {
+            let key = rng.Key();
+            let value = rng.Next();
+            if rng.Next() % 4 == 0 {
+                commit.Delete(key);
+                model.remove(&key);
+            } else {
+                commit.Put(key, value);
+                model.insert(key, value);
+            }
+        }
+        trie.ApplyCommit(commit);
+        held.push((trie.Snapshot(), model.clone()));
+    }
+
+    for (snapshot, snapshot_model) in held.iter() {
+        for (key, value) in snapshot_model.iter() {
+            assert_eq!(trie.Get(*snapshot, *key).map(|v| *v), Some(*value));
+        }
+    }
+
+    //the diff between two held versions matches the diff between their models
+    let (old, old_model) = &held[10];
+    let (new, new_model) = &held[40];
+    let mut changes = Vec::new();
+    trie.Diff(*old, *new, |key, before, after| {
+        assert!(!before.IsNull() || !after.IsNull());
+        changes.push(key);
+    });
+    let mut expected: Vec<_> = old_model
+        .keys()
+        .chain(new_model.keys())
+        .filter(|key| old_model.get(key) != new_model.get(key))
+        .copied()
+        .collect();
+    expected.sort();
+    expected.dedup();
+    assert_eq!(changes, expected);
+
+    for (snapshot, _) in held.drain(..) {
+        trie.ReleaseSnapshot(snapshot);
+    }
+    //only the current version is left, every value belongs to exactly one live key
+    assert_eq!(trie.ValueStats().live, model.len());
+    AssertMatches(&trie, &model);
+}
+
+#[test]
+fn delete_collapses_nodes() {
+    let mut trie = Trie::<u64>::new();
+    //differ only in the last byte, so they split all the way down
+    let mut commit = Commit::new();
+    commit
+        .Put(0x1122_3344_5566_7700, 1)
+        .Put(0x1122_3344_5566_7701, 2);
+    trie.ApplyCommit(commit);
+    assert_eq!(trie.NodeStats().live, 8);
+
+    let mut commit = Commit::new();
+    commit.Delete(0x1122_3344_5566_7701);
+    trie.ApplyCommit(commit);
+    assert_eq!(trie.NodeStats().live, 1);
+    assert_eq!(*trie.Get(trie.Root(), 0x1122_3344_5566_7700).unwrap(), 1);
+
+    let mut rng = Rng(0xD1B54A32D192ED03);
+    let keys: Vec<i64> = (0..500).map(|_| rng.Key()).collect();
+    let mut commit = Commit::new();
+    for key in keys.iter() {
+        commit.Put(*key, 0);
+    }
+    trie.ApplyCommit(commit);
+    assert!(trie.NodeStats().live > 1);
+
+    let mut commit = Commit::new();
+    for key in keys.iter() {
+        commit.Delete(*key);
+    }
+    trie.ApplyCommit(commit);
+    assert_eq!(trie.NodeStats().live, 1);
+    assert_eq!(trie.ValueStats().live, 1);
+}
+
+#[test]
+fn scans_match_btree_ranges() {
+    let mut rng = Rng(0xBF58476D1CE4E5B9);
+    let mut trie = Trie::<u64>::new();
+    let mut model = BTreeMap::new();
+    let mut commit = Commit::new();
+    for _ in 0..1000 {
+        let key = rng.Key();
+        let value = rng.Next();
+        commit.Put(key, value);
+        model.insert(key, value);
+    }
+    trie.ApplyCommit(commit);
+    let root = trie.Root();
+
+    let all: Vec<_> = trie.Iter(root).map(|(k, v)| (k, *v)).collect();
+    assert_eq!(all, model.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>());
+
+    for _ in 0..200 {
+        let (a, b) = (rng.Key(), rng.Key());
+        let (lo, hi) = (a.min(b), a.max(b));
+        let scanned: Vec<_> = trie.Range(root, lo..hi).map(|(k, _)| k).collect();
+        let expected: Vec<_> = model.range(lo..hi).map(|(k, _)| *k).collect();
+        assert_eq!(scanned, expected);
+
+        let scanned: Vec<_> = trie.Range(root, lo..=hi).map(|(k, _)| k).collect();
+        let expected: Vec<_> = model.range(lo..=hi).map(|(k, _)| *k).collect();
+        assert_eq!(scanned, expected);
+    }
+
+    assert_eq!(trie.Range(root, ..0).count(), model.range(..0).count());
+    assert_eq!(trie.Range(root, 0..).count(), model.range(0..).count());
+    assert_eq!(trie.Range(root, 5..5).count(), 0);
+}
+
+#[test]
+fn prefix_scan_selects_entity_kind() {
+    let mut trie = Trie::<u64>::new();
+    let mut commit = Commit::new();
+    for kind in 1..4i64 {
+        for id in 0..100i64 {
+            commit.Put((kind << 56) | id, kind as u64);
+        }
+    }
+    trie.ApplyCommit(commit);
+
+    let kind_two: Vec<_> = trie.Prefix(trie.Root(), 2 << 56, 1).collect();
+    assert_eq!(kind_two.len(), 100);
+    assert!(kind_two
+        .iter()
+        .all(|(key, value)| key >> 56 == 2 && **value == 2));
+    assert!(kind_two.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    assert_eq!(trie.Prefix(trie.Root(), 4 << 56, 1).count(), 0);
+    assert_eq!(trie.Prefix(trie.Root(), (3 << 56) | 7, 8).count(), 1);
+    assert_eq!(trie.Prefix(trie.Root(), 0, 0).count(), 300);
+}
+
+#[test]
+fn prefix_longer_than_key() {
+    let mut trie = Trie::<u32, u16>::new();
+    let mut commit = Commit::new();
+    commit.Put(0x0102, 1).Put(0x0103, 2).Put(0x0201, 3);
+    trie.ApplyCommit(commit);
+
+    //a u16 key has 2 bytes, asking for 3 matches the whole key
+    let matched: Vec<_> = trie
+        .Prefix(trie.Root(), 0x0103, 3)
+        .map(|(key, value)| (key, *value))
+        .collect();
+    assert_eq!(matched, vec![(0x0103, 2)]);
+    assert_eq!(trie.Prefix(trie.Root(), 0x0104, usize::MAX).count(), 0);
+}
+
+#[test]
+fn root_hash_tracks_content() {
+    let mut rng = Rng(0x94D049BB133111EB);
+    let keys: Vec<i64> = (0..300).map(|_| rng.Key()).collect();
+
+    //same content built in a different order, in a different trie
+    let mut forward = Trie::<u64>::new();
+    let mut backward = Trie::<u64>::new();
+    let empty_hash = forward.RootHash(forward.Root());
+    for key in keys.iter() {
+        let mut commit = Commit::new();
+        commit.Put(*key, *key as u64);
+        forward.ApplyCommit(commit);
+    }
+    let mut commit = Commit::new();
+    for key in keys.iter().rev() {
+        commit.Put(*key, *key as u64);
+    }
+    backward.ApplyCommit(commit);
+    assert_eq!(
+        forward.RootHash(forward.Root()),
+        backward.RootHash(backward.Root())
+    );
+    assert_ne!(forward.RootHash(forward.Root()), empty_hash);
+
+    let before = forward.Snapshot();
+    let mut commit = Commit::new();
+    commit.Put(keys[0], 12345);
+    forward.ApplyCommit(commit);
+    assert_ne!(forward.RootHash(forward.Root()), forward.RootHash(before));
+
+    //writing the old value back restores the hash, and the diff sees no change
+    let mut commit = Commit::new();
+    commit.Put(keys[0], keys[0] as u64);
+    forward.ApplyCommit(commit);
+    assert_eq!(forward.RootHash(forward.Root()), forward.RootHash(before));
+    let mut changes = 0;
+    forward.Diff(before, forward.Root(), |_, _, _| changes += 1);
+    assert_eq!(changes, 0);
+    forward.ReleaseSnapshot(before);
+
+    let mut commit = Commit::new();
+    for key in keys.iter() {
+        commit.Delete(*key);
+    }
+    forward.ApplyCommit(commit);
+    assert_eq!(forward.RootHash(forward.Root()), empty_hash);
+    assert_eq!(forward.RootHash(handle::handle_t::null()), empty_hash);
+}
+
+struct Creature {
+    name: String,
+    items: Vec<String>,
+    alive: Rc<()>,
+}
+
+trie_hash!(Creature { name, items, alive });
+
+#[test]
+fn owned_values_keyed_by_handle() {
+    let alive = Rc::new(());
+    let creature = |name: &str, items: &[&str]| Creature {
+        name: name.to_string(),
+        items: items.iter().map(|item| item.to_string()).collect(),
+        alive: alive.clone(),
+    };
+    let goblin = handle::handle_t::<Creature>::from(1, 0, 3);
+    let troll = handle::handle_t::<Creature>::from(2, 0, 1);
+    let orc = handle::handle_t::<Creature>::from(1, 7, 0);
+
+    let mut trie = Trie::<Creature, handle::handle_t<Creature>>::new();
+    let mut commit = Commit::new();
+    commit
+        .Put(goblin, creature("goblin", &["dagger"]))
+        .Put(troll, creature("troll", &["club", "rock"]))
+        .Put(orc, creature("orc", &[]));
+    trie.ApplyCommit(commit);
+    let before = trie.Snapshot();
+
+    let mut commit = Commit::new();
+    commit
+        .Put(goblin, creature("goblin", &["dagger", "torch"]))
+        .Delete(orc);
+    trie.ApplyCommit(commit);
+
+    assert_eq!(trie.Get(trie.Root(), goblin).unwrap().items.len(), 2);
+    assert_eq!(trie.Get(before, goblin).unwrap().items.len(), 1);
+    assert!(trie.Get(trie.Root(), orc).is_none());
+    assert_eq!(trie.Get(before, orc).unwrap().name, "orc");
+
+    //handles iterate in raw value order
+    let mut expected = vec![goblin, troll, orc];
+    expected.sort();
+    let keys: Vec<_> = trie.Iter(before).map(|(key, _)| key).collect();
+    assert_eq!(keys, expected);
+
+    let mut changed = Vec::new();
+    trie.Diff(before, trie.Root(), |key, _, _| changed.push(key));
+    changed.sort();
+    let mut expected = vec![goblin, orc];
+    expected.sort();
+    assert_eq!(changed, expected);
+
+    //the old goblin and the orc only lived in the snapshot
+    assert_eq!(Rc::strong_count(&alive), 5);
+    trie.ReleaseSnapshot(before);
+    assert_eq!(Rc::strong_count(&alive), 3);
+    drop(trie);
+    assert_eq!(Rc::strong_count(&alive), 1);
+}
+
+#[test]
+fn float_values() {
+    let mut trie = Trie::<(f32, f64)>::new();
+    let mut commit = Commit::new();
+    commit.Put(1, (0.5, -2.25)).Put(2, (f32::NAN, 0.0));
+    trie.ApplyCommit(commit);
+    let before = trie.Snapshot();
+    assert_eq!(trie.Get(trie.Root(), 1).unwrap().1, -2.25);
+
+    //the same bits hash the same, NaN included, so rewriting them leaves the root hash alone
+    let mut commit = Commit::new();
+    commit.Put(2, (f32::NAN, 0.0));
+    trie.ApplyCommit(commit);
+    assert_eq!(trie.RootHash(trie.Root()), trie.RootHash(before));
+
+    let mut commit = Commit::new();
+    commit.Put(2, (f32::NAN, -0.0));
+    trie.ApplyCommit(commit);
+    assert_ne!(trie.RootHash(trie.Root()), trie.RootHash(before));
+}
+
+#[derive(Clone, Hash)]
+enum Faction {
+    Goblins,
+    Trolls,
+}
+
+trie_hash!(std: Faction);
+
+#[derive(Clone)]
+struct Unit {
+    faction: Faction,
+    position: (f32, f32),
+}
+
+trie_hash!(Unit { faction, position });
+
+#[test]
+fn game_types_opt_in_with_the_macro() {
+    let mut trie = Trie::<Unit>::new();
+    let mut commit = Commit::new();
+    commit.Put(
+        1,
+        Unit {
+            faction: Faction::Goblins,
+            position: (1.0, 2.0),
+        },
+    );
+    trie.ApplyCommit(commit);
+    let before = trie.Snapshot();
+
+    //every field feeds the hash, the enum through its std Hash
+    let mut commit = Commit::new();
+    commit.Put(
+        1,
+        Unit {
+            faction: Faction::Trolls,
+            position: (1.0, 2.0),
+        },
+    );
+    trie.ApplyCommit(commit);
+    assert_ne!(trie.RootHash(trie.Root()), trie.RootHash(before));
+
+    let mut commit = Commit::new();
+    commit.Put(
+        1,
+        Unit {
+            faction: Faction::Goblins,
+            position: (1.0, 2.0),
+        },
+    );
+    trie.ApplyCommit(commit);
+    assert_eq!(trie.RootHash(trie.Root()), trie.RootHash(before));
+    trie.ReleaseSnapshot(before);
+}
+
+#[test]
+fn entity_id_keys() {
+    let mut trie = Trie::<u32, u64>::new();
+    let mut commit = Commit::new();
+    for id in [0, 1, 255, 256, 1 << 40, u64::MAX - 1, u64::MAX] {
+        commit.Put(id, id as u32);
+    }
+    trie.ApplyCommit(commit);
+
+    let keys: Vec<_> = trie.Range(trie.Root(), 255..).map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![255, 256, 1 << 40, u64::MAX - 1, u64::MAX]);
+    assert_eq!(trie.Prefix(trie.Root(), 0, 7).count(), 3);
+    assert_eq!(*trie.Get(trie.Root(), u64::MAX).unwrap(), u32::MAX);
+}
+
+#[test]
+fn merge_combines_and_resolves_conflicts() {
+    let mut trie = Trie::<u64>::new();
+    let mut commit = Commit::new();
+    for key in 0..6 {
+        commit.Put(key, 100 + key as u64);
+    }
+    trie.ApplyCommit(commit);
+    let base = trie.Snapshot();
+
+    //combat
+    let mut ours = Commit::new();
+    ours.Put(0, 1)
+        .Delete(1)
+        .Put(2, 7)
+        .Put(3, 8)
+        .Put(4, 9)
+        .Delete(5);
+    //movement
+    let mut theirs = Commit::new();
+    theirs
+        .Put(0, 2)
+        .Put(2, 7)
+        .Put(3, 103)
+        .Put(4, 10)
+        .Delete(5)
+        .Put(6, 60);
+
+    let mut conflicts = Vec::new();
+    let merged = trie.Merge(base, ours, theirs, |key, base, ours, theirs| {
+        conflicts.push((key, base.copied(), ours, theirs));
+        return Some(ours.unwrap() + theirs.unwrap());
+    });
+    trie.ApplyCommit(merged);
+
+    //0 and 4 were changed differently on both sides, 2 the same, 3 only by us
+    assert_eq!(
+        conflicts,
+        vec![
+            (0, Some(100), Some(1), Some(2)),
+            (4, Some(104), Some(9), Some(10))
+        ]
+    );
+    let root = trie.Root();
+    let contents: Vec<_> = trie.Iter(root).map(|(k, v)| (k, *v)).collect();
+    assert_eq!(contents, vec![(0, 3), (2, 7), (3, 8), (4, 19), (6, 60)]);
+    trie.ReleaseSnapshot(base);
+}
+
+#[test]
+fn merge_of_disjoint_commits_matches_applying_both() {
+    let mut rng = Rng(0x3C79AC492BA7B653);
+    let mut merged_trie = Trie::<u64>::new();
+    let mut sequential = Trie::<u64>::new();
+
+    for _ in 0..50 {
+        let mut ours = Commit::new();
+        let mut theirs = Commit::new();
+        let mut ours_again = Commit::new();
+        let mut theirs_again = Commit::new();
+        for _ in 0..(rng.Next() % 16) {
+            //even keys for one system, odd for the other
+            let key = rng.Key() & !1;
+            let value = rng.Next();
+            ours.Put(key, value);
+            ours_again.Put(key, value);
+            theirs.Put(key | 1, value);
+            theirs_again.Put(key | 1, value);
+        }
+
+        let merged = merged_trie.Merge(merged_trie.Root(), ours, theirs, |_, _, _, _| {
+            panic!("disjoint commits can't conflict")
+        });
+        merged_trie.ApplyCommit(merged);
+        sequential.ApplyCommit(ours_again);
+        sequential.ApplyCommit(theirs_again);
+        assert_eq!(
+            merged_trie.RootHash(merged_trie.Root()),
+            sequential.RootHash(sequential.Root())
+        );
+    }
+}